@@ -11,21 +11,49 @@
 // `CostsDatabase` trait from this nested module.
 mod implementation {
     // The meaning of these types is explained in src/lib.rs, which is best read from top to bottom.
-    use dip::{Database, Key, QueryId, Value};
-    use std::collections::HashMap;
+    use dip::{Database, DatabaseBuilder, Query, QueryContext, QueryId};
 
-    // All dip queries have signature (&mut Database, Key) -> Value, but we define some type
-    // aliases to make the example code easier to follow.
+    // Type aliases to make the example code easier to follow.
     type Dollars = i32;
     type Years = i32;
 
-    // Identifiers for the queries (inputs or derived) used in our example.
+    // The queries (inputs or derived) used in our example. Each query declares its key and value
+    // types, along with the id used to identify it within the database.
     // We hide these from the end-user behind the `CostsDatabase` trait below.
-    const BASE_FEE: QueryId = "base_fee";
-    const DISCOUNT_AGE_LIMIT: QueryId = "discount_age_limit";
-    const DISCOUNT_AMOUNT: QueryId = "discount_amount";
-    const ONE_YEAR_FEE: QueryId = "one_year_fee";
-    const TWO_YEAR_FEE: QueryId = "two_year_fee";
+    struct BaseFee;
+    impl Query for BaseFee {
+        type Key = ();
+        type Value = Dollars;
+        const ID: QueryId = "base_fee";
+    }
+
+    struct DiscountAgeLimit;
+    impl Query for DiscountAgeLimit {
+        type Key = ();
+        type Value = Years;
+        const ID: QueryId = "discount_age_limit";
+    }
+
+    struct DiscountAmount;
+    impl Query for DiscountAmount {
+        type Key = ();
+        type Value = Dollars;
+        const ID: QueryId = "discount_amount";
+    }
+
+    struct OneYearFee;
+    impl Query for OneYearFee {
+        type Key = Years;
+        type Value = Dollars;
+        const ID: QueryId = "one_year_fee";
+    }
+
+    struct TwoYearFee;
+    impl Query for TwoYearFee {
+        type Key = Years;
+        type Value = Dollars;
+        const ID: QueryId = "two_year_fee";
+    }
 
     // This trait allows for more ergonomic-looking code in this example's `main` function, but
     // has no special significance - we could equally well have written this example with
@@ -38,99 +66,83 @@ mod implementation {
         fn set_base_fee(&mut self, base_fee: Dollars);
         fn set_discount_amount(&mut self, discount_amount: Dollars);
 
-        // Reading inputs
-        fn discount_age_limit(&mut self) -> Years;
-        fn base_fee(&mut self) -> Dollars;
-        fn discount_amount(&mut self) -> Dollars;
-
         // Derived queries
         fn one_year_fee(&mut self, current_age: Years) -> Dollars;
         fn two_year_fee(&mut self, current_age: Years) -> Dollars;
     }
 
     impl CostsDatabase for Database {
-        // The ids DISCOUNT_AGE_LIMIT, BASE_FEE and DISCOUNT_AMOUNT are registered as input ids
-        // in the call to `dip::Database::new` in the `create_database` function below.
+        // DiscountAgeLimit, BaseFee and DiscountAmount are registered as input queries
+        // using `dip::DatabaseBuilder` in the `create_database` function below.
         //
-        // Input queries take an `Into<Key>` as input. In our example they have no logical inputs,
-        // so we use `()`.
+        // The key and value types are checked at compile time. In our example the input queries
+        // have no logical inputs, so their key type is `()`.
         fn set_discount_age_limit(&mut self, age_limit: Years) {
-            self.set(DISCOUNT_AGE_LIMIT, (), age_limit);
+            self.set::<DiscountAgeLimit>((), age_limit);
         }
         fn set_base_fee(&mut self, base_fee: Dollars) {
-            self.set(BASE_FEE, (), base_fee);
+            self.set::<BaseFee>((), base_fee);
         }
         fn set_discount_amount(&mut self, discount_amount: Dollars) {
-            self.set(DISCOUNT_AMOUNT, (), discount_amount);
-        }
-
-        // The API for querying inputs is identical to non-input queries.
-        fn discount_age_limit(&mut self) -> Years {
-            self.get(DISCOUNT_AGE_LIMIT, ())
-        }
-        fn base_fee(&mut self) -> Dollars {
-            self.get(BASE_FEE, ())
-        }
-        fn discount_amount(&mut self) -> Dollars {
-            self.get(DISCOUNT_AMOUNT, ())
+            self.set::<DiscountAmount>((), discount_amount);
         }
 
         // Compute the one year membership fee for someone of the given age.
         fn one_year_fee(&mut self, current_age: Years) -> Dollars {
-            self.get(ONE_YEAR_FEE, current_age)
+            self.get::<OneYearFee>(current_age)
         }
 
         // Compute the two year membership fee for someone of the given age.
         fn two_year_fee(&mut self, current_age: Years) -> Dollars {
-            self.get(TWO_YEAR_FEE, current_age)
+            self.get::<TwoYearFee>(current_age)
         }
     }
 
     // See comments in `create_database`.
-    fn one_year_fee_query(db: &mut Database, current_age: Key) -> Dollars {
-        let current_age: Years = current_age.into();
-
+    //
+    // Query functions are passed a `QueryContext` rather than the `Database` itself. This lets them
+    // read other queries, but not set inputs. The API for querying inputs is identical to non-input
+    // queries.
+    fn one_year_fee_query(ctx: &mut QueryContext, current_age: Years) -> Dollars {
         // Customers receive a discount if they're <= the discount age limit.
-        if current_age <= db.discount_age_limit() {
-            db.base_fee() - db.discount_amount()
+        if current_age <= ctx.get::<DiscountAgeLimit>(()) {
+            ctx.get::<BaseFee>(()) - ctx.get::<DiscountAmount>(())
         } else {
-            db.base_fee()
+            ctx.get::<BaseFee>(())
         }
     }
 
     // See comments in `create_database`.
-    fn two_year_fee_query(db: &mut Database, current_age: Key) -> Dollars {
-        let current_age: Years = current_age.into();
-
+    fn two_year_fee_query(ctx: &mut QueryContext, current_age: Years) -> Dollars {
         // Compute the fees for this year and next year and add them (no loyalty discounts here).
         //
         // This is equal to `2 * one_year_fee` _unless_ you're currently at the age limit for a
         // young person's discount.
-        let fee_this_year = db.one_year_fee(current_age);
-        let fee_next_year = db.one_year_fee(current_age + 1);
+        let fee_this_year = ctx.get::<OneYearFee>(current_age);
+        let fee_next_year = ctx.get::<OneYearFee>(current_age + 1);
         fee_this_year + fee_next_year
     }
 
     pub fn create_database() -> impl CostsDatabase {
         // Unlike in salsa, our users need to wire up all the queries themselves.
-        //
-        // First, we define the set of input ids. These are queries whose values must be provided
-        // directly by the user.
-        let input_ids = vec![BASE_FEE, DISCOUNT_AGE_LIMIT, DISCOUNT_AMOUNT];
-
-        // Dependency tracking and memoisation is defined in terms of QueryIds. If dip determines
-        // that it needs to (re)compute some value then it needs to be able to look up the
-        // appropriate query function from its id. This lookup is provided directly in the
-        // constructor to `Database`.
-        let mut query_functions = HashMap::<QueryId, Box<fn(&mut Database, Key) -> Value>>::new();
-
-        // Note that we only need to register functions for derived queries - no user-provided code
-        // is executed when reading input queries as we just read their cached values directly.
-        query_functions.insert(ONE_YEAR_FEE, Box::new(one_year_fee_query));
-        query_functions.insert(TWO_YEAR_FEE, Box::new(two_year_fee_query));
-
-        // Return a configured database and hide the plumbing from the end-users behind a trait.
-        Database::new(input_ids, query_functions)
+        DatabaseBuilder::new()
+            // First, we register the input queries. These are queries whose values must be provided
+            // directly by the user.
+            .add_input::<BaseFee>()
+            .add_input::<DiscountAgeLimit>()
+            .add_input::<DiscountAmount>()
+            // Dependency tracking and memoisation is defined in terms of QueryIds. If dip determines
+            // that it needs to (re)compute some value then it needs to be able to look up the
+            // appropriate query function from its id, so we register a function for each derived query.
+            //
+            // Note that we only need to register functions for derived queries - no user-provided code
+            // is executed when reading input queries as we just read their cached values directly.
+            .add_derived::<OneYearFee>(one_year_fee_query)
+            .add_derived::<TwoYearFee>(two_year_fee_query)
+            // `build` checks that we haven't registered the same id twice.
+            .build()
+            // Return a configured database and hide the plumbing from the end-users behind a trait.
+            .expect("queries are registered correctly")
     }
 }
 
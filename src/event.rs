@@ -1,26 +1,44 @@
 //! An `Event` type, and helper functions to log these to the console.
 //! These are solely for debugging and tracing purposes - they do not affect query evaluation.
 
-use crate::{Key, Memo, Slot, Value};
+use crate::{Durability, Key, Memo, Slot, Value};
 use std::fmt::Write;
 
 /// `Database` is currently hardcoded to use `EventLogger` to log these events to the console.
 #[derive(Clone)]
 pub(crate) enum Event {
     Set(Slot, Value, usize),
+    Remove(Slot, usize),
+    Load(usize, usize),
     Get(Slot),
     StartedQueryEvaluation,
     CompletedQueryEvaluation,
     StoreMemo(Option<Memo>, Memo),
     ReadMemo(Option<Memo>),
+    EvictMemo(Slot),
+    Sweep(usize, bool),
+    NotifySubscriber(Slot, usize),
+    Accumulate(Value),
+    Cancelled,
+    WorkerCancelled,
+    WaitForOtherThread(Slot),
+    DeferRead(Slot),
+    UseDeferredValue,
     MemoForInputQuery,
     MemoVerifiedAtCurrentRevision,
+    MemoValidByDurability(Durability, usize),
+    MemoForVolatileQuery,
+    MemoHasNoValue,
+    TransparentQuery,
     ValueComparison(Value, Value, usize),
     StartedInputChecks(usize),
+    StartedParallelInputChecks(usize, usize),
     CompletedInputChecks(bool),
     ChangedAt(Slot, usize),
     PushActiveQuery,
     PopActiveQuery,
+    ReadProvisionalValue(Value),
+    StartedFixpointIteration(usize, Value),
 }
 
 /// Logs `Events` to the console.
@@ -31,7 +49,7 @@ pub(crate) struct EventLogger {
 /// Helper macro used in `EventLogger` to make it slightly less verbose to log indented lines.
 macro_rules! log {
     ($self:expr, $($arg:tt)+) => {{
-        print!("{}", Self::TAB.repeat($self.indent));
+        print!("{}", Self::TAB.repeat($self.indent.min(Self::MAX_INDENT)));
         println!($($arg)+)
     }}
 }
@@ -47,13 +65,21 @@ impl EventLogger {
             Event::Set(slot, value, revision) => {
                 log!(
                     self,
-                    "Setting ({}, {}) to {}",
+                    "Setting ({}, {}) to {:?}",
                     slot.id,
                     print_key(&slot.key),
                     value
                 );
                 log!(self, "Global revision is now {}", revision);
             }
+            Event::Remove(slot, revision) => {
+                log!(self, "Removing ({}, {})", slot.id, print_key(&slot.key));
+                log!(self, "Global revision is now {}", revision);
+            }
+            Event::Load(count, revision) => {
+                log!(self, "Loaded {} saved memos", count);
+                log!(self, "Global revision is now {}", revision);
+            }
             Event::Get(slot) => {
                 log!(self, "Query {}", print_slot_as_function_call(slot));
             }
@@ -77,14 +103,36 @@ impl EventLogger {
                     None => log!(self, "No memo currently exists"),
                 };
             }
+            Event::EvictMemo(slot) => {
+                log!(
+                    self,
+                    "Evicting least recently used memo for {}",
+                    print_slot_as_function_call(slot)
+                );
+            }
+            Event::Sweep(count, values_only) => match values_only {
+                true => log!(self, "Sweeping: discarding the values of {} memos", count),
+                false => log!(self, "Sweeping: discarding {} memos", count),
+            },
+            Event::NotifySubscriber(slot, changed_at) => {
+                log!(
+                    self,
+                    "Notifying subscriber as {} changed at revision {}",
+                    print_slot_as_function_call(slot),
+                    changed_at
+                );
+            }
+            Event::Accumulate(item) => {
+                log!(self, "Accumulating {:?}", item);
+            }
             Event::ValueComparison(old_value, new_value, current_revision) => {
                 let result = match old_value == new_value {
                     true => format!(
-                        "New value {} is the same as the memo value, so not updating changed_at",
+                        "New value {:?} is the same as the memo value, so not updating changed_at",
                         new_value
                     ),
                     false => format!(
-                        "New value {} != memo value {}, so updating changed_at to {}",
+                        "New value {:?} != memo value {:?}, so updating changed_at to {}",
                         new_value, old_value, current_revision
                     ),
                 };
@@ -98,6 +146,14 @@ impl EventLogger {
                 );
                 self.indent += 1;
             }
+            Event::StartedParallelInputChecks(count, workers) => {
+                log!(
+                    self,
+                    "Checking the remaining {} inputs on {} worker threads",
+                    count,
+                    workers
+                );
+            }
             Event::CompletedInputChecks(any_inputs_have_changed) => {
                 self.indent -= 1;
                 let result = match any_inputs_have_changed {
@@ -106,6 +162,32 @@ impl EventLogger {
                 };
                 log!(self, "Memo is {}", result)
             }
+            Event::Cancelled => {
+                log!(self, "Cancelling as an input is waiting to be changed");
+            }
+            Event::WorkerCancelled => {
+                log!(self, "Cancelling as an earlier dependency has changed");
+            }
+            Event::WaitForOtherThread(slot) => {
+                log!(
+                    self,
+                    "Waiting for another thread to finish running {}",
+                    print_slot_as_function_call(slot)
+                );
+            }
+            Event::DeferRead(slot) => {
+                log!(
+                    self,
+                    "Too many query functions are running, so abandoning them to run {} first",
+                    print_slot_as_function_call(slot)
+                );
+            }
+            Event::UseDeferredValue => {
+                log!(
+                    self,
+                    "Too many query functions are running, so using the value from the deferred read"
+                );
+            }
             Event::MemoForInputQuery => {
                 log!(self, "Memo is valid as this is an input query");
             }
@@ -115,6 +197,32 @@ impl EventLogger {
                     "Memo is valid as it was verified at the current revision"
                 );
             }
+            Event::MemoForVolatileQuery => {
+                log!(
+                    self,
+                    "Memo is invalid as this is a volatile query verified at an earlier revision"
+                );
+            }
+            Event::MemoHasNoValue => {
+                log!(
+                    self,
+                    "Memo is valid but its value has been discarded, so rerunning query function"
+                );
+            }
+            Event::TransparentQuery => {
+                log!(
+                    self,
+                    "Query is transparent, so running its query function without memoizing"
+                );
+            }
+            Event::MemoValidByDurability(durability, last_changed) => {
+                log!(
+                    self,
+                    "Memo is valid as no inputs with durability >= {:?} have changed since revision {}",
+                    durability,
+                    last_changed
+                );
+            }
             Event::ChangedAt(slot, changed_at) => {
                 log!(
                     self,
@@ -129,6 +237,21 @@ impl EventLogger {
             Event::PopActiveQuery => {
                 self.pop();
             }
+            Event::ReadProvisionalValue(value) => {
+                log!(
+                    self,
+                    "Query is already active, so using its provisional value {:?}",
+                    value
+                );
+            }
+            Event::StartedFixpointIteration(iteration, value) => {
+                log!(
+                    self,
+                    "Starting fixpoint iteration {} with provisional value {:?}",
+                    iteration,
+                    value
+                );
+            }
         };
     }
 
@@ -141,11 +264,15 @@ impl EventLogger {
     }
 
     const TAB: &'static str = "|  ";
+
+    /// Lines aren't indented any further than this, so that logging a long chain of queries doesn't print a
+    /// quadratic amount of indentation.
+    const MAX_INDENT: usize = 50;
 }
 
 fn print_memo(memo: &Memo) -> String {
     let mut dependencies = String::new();
-    write!(&mut dependencies, "{{").unwrap();
+    write!(&mut dependencies, "[").unwrap();
     let mut first = true;
     for dependency in &memo.dependencies {
         if !first {
@@ -160,25 +287,26 @@ fn print_memo(memo: &Memo) -> String {
         .unwrap();
         first = false;
     }
-    write!(&mut dependencies, "}}").unwrap();
+    write!(&mut dependencies, "]").unwrap();
+    let value = match &memo.value {
+        Some(value) => format!("{:?}", value),
+        None => "<discarded>".to_string(),
+    };
     format!(
         "(value: {}, verified_at: {}, changed_at: {}, dependencies: {})",
-        memo.value, memo.verified_at, memo.changed_at, dependencies
+        value, memo.verified_at, memo.changed_at, dependencies
     )
 }
 
 fn print_slot_as_function_call(slot: &Slot) -> String {
-    let v = match slot.key {
-        Key::Void => "".to_string(),
-        Key::Int(x) => x.to_string(),
+    // Queries with key type `()` are printed as `query()` rather than `query(())`.
+    let v = match slot.key.is_unit() {
+        true => "".to_string(),
+        false => print_key(&slot.key),
     };
     format!("{}({})", slot.id, v)
 }
 
 fn print_key(key: &Key) -> String {
-    let v = match key {
-        Key::Void => "()".to_string(),
-        Key::Int(x) => x.to_string(),
-    };
-    format!("{}", v)
+    format!("{:?}", key)
 }
@@ -1,51 +1,162 @@
-//! This file contains the whole framework implementation, except for some logging code in events.rs.
+//! This file contains the whole framework implementation, except for some logging code in events.rs and the
+//! encodings used when saving databases in persist.rs.
 //! It is intended to be readable from top to bottom.
 
-use std::fmt::Debug;
+use std::any::{Any, TypeId};
+use std::convert::TryInto;
+use std::fmt::{self, Debug, Display};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
 use std::{
-    collections::{HashMap, HashSet},
-    hash::Hash,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    hash::{Hash, Hasher},
+    io,
 };
 
 // The `event` module contains logging code only - it can safely be ignored when reading this file.
 pub mod event;
 use event::{Event, EventLogger};
 
-// Salsa supports custom key and value types for queries.
-// Dip does not - all keys must be of type `Key`, and all outputs must be of type `Value`.
-pub type Value = i32;
+// The `persist` module converts keys and values to and from bytes for `Database::save` and `Database::load`.
+mod persist;
+pub use persist::Persist;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub enum Key {
-    Void,
-    Int(i32),
+#[cfg(test)]
+mod tests;
+
+/// Every query is used like a function from some key type to some value type.
+///
+/// Like salsa, each query declares its own key and value types. Unlike salsa, there are no procedural
+/// macros to generate this boilerplate - users define a (typically empty) struct per query and implement
+/// this trait for it by hand. See `examples/walkthrough.rs` for some examples.
+///
+/// Keys and values are shared between the threads using a `Database` and its `Snapshot`s, so they need to be
+/// `Send + Sync`.
+pub trait Query: 'static {
+    /// The input to the query. Keys are used to index the query's cached results, so they need to be
+    /// hashable. Queries which logically take no input can use `()`.
+    type Key: Clone + Debug + Eq + Hash + Send + Sync + 'static;
+    /// The output of the query. Values need to support equality so that the database can tell whether
+    /// rerunning a query has actually changed its output.
+    type Value: Clone + Debug + Eq + Send + Sync + 'static;
+    /// Identifies this query within a `Database`.
+    const ID: QueryId;
 }
 
-// Some From/Into impls for `Key`, as a minor concession to user ergonomics.
-impl From<()> for Key {
-    fn from(_: ()) -> Self {
-        Key::Void
+// The `Database` needs to store keys and values for many different queries in the same collections, so
+// internally it works with the type-erased `Key` and `Value` types defined below. The public methods on
+// `Database` convert to and from the concrete types declared by each `Query`.
+//
+// You don't need to understand the details of how the type erasure works to follow the rest of this file -
+// it's enough to know that `Key`s and `Value`s can be compared for equality, `Key`s can be hashed, and that
+// we can convert back to the original types.
+
+/// A query key whose concrete type has been erased.
+///
+/// `Key`s are only exposed to users in `DipError`s, where `downcast_ref` can be used to recover the
+/// original key.
+#[derive(Clone)]
+pub struct Key(Arc<dyn DynKey>);
+
+impl Key {
+    fn new<K: Debug + Eq + Hash + Send + Sync + 'static>(key: K) -> Self {
+        Key(Arc::new(key))
+    }
+
+    /// Returns the original key, if it has type `K`.
+    pub fn downcast_ref<K: 'static>(&self) -> Option<&K> {
+        self.0.as_any().downcast_ref::<K>()
+    }
+
+    fn is_unit(&self) -> bool {
+        self.0.as_any().is::<()>()
     }
 }
-impl From<i32> for Key {
-    fn from(x: i32) -> Self {
-        Key::Int(x)
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        self.0.dyn_eq(other.0.as_any())
     }
 }
-impl From<Key> for () {
-    fn from(key: Key) -> () {
-        match key {
-            Key::Void => (),
-            _ => panic!("Key type mismatch"),
-        }
+impl Eq for Key {}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.dyn_hash(state)
     }
 }
-impl From<Key> for i32 {
-    fn from(key: Key) -> i32 {
-        match key {
-            Key::Int(x) => x,
-            _ => panic!("Key type mismatch"),
-        }
+
+impl Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The object-safe subset of the traits required of `Query::Key`.
+trait DynKey: Debug + Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn dyn_eq(&self, other: &dyn Any) -> bool;
+    fn dyn_hash(&self, state: &mut dyn Hasher);
+}
+
+impl<K: Debug + Eq + Hash + Send + Sync + 'static> DynKey for K {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn dyn_eq(&self, other: &dyn Any) -> bool {
+        other.downcast_ref::<K>() == Some(self)
+    }
+    fn dyn_hash(&self, mut state: &mut dyn Hasher) {
+        self.hash(&mut state)
+    }
+}
+
+/// A query output whose concrete type has been erased.
+#[derive(Clone)]
+struct Value(Arc<dyn DynValue>);
+
+impl Value {
+    fn new<V: Debug + Eq + Send + Sync + 'static>(value: V) -> Self {
+        Value(Arc::new(value))
+    }
+
+    fn downcast<V: Clone + 'static>(&self) -> Option<V> {
+        self.downcast_ref::<V>().cloned()
+    }
+
+    fn downcast_ref<V: 'static>(&self) -> Option<&V> {
+        self.0.as_any().downcast_ref::<V>()
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        self.0.dyn_eq(other.0.as_any())
+    }
+}
+
+impl Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The object-safe subset of the traits required of `Query::Value`.
+trait DynValue: Debug + Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn dyn_eq(&self, other: &dyn Any) -> bool;
+}
+
+impl<V: Debug + Eq + Send + Sync + 'static> DynValue for V {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn dyn_eq(&self, other: &dyn Any) -> bool {
+        other.downcast_ref::<V>() == Some(self)
     }
 }
 
@@ -61,7 +172,7 @@ pub type QueryId = &'static str;
 /// A `Slot` identifies a location in which to cache a query result.
 /// Every query takes a `Key` as input, and to uniquely identify a query evaluation
 /// you need to know both the id of the query and the inputs used.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 struct Slot {
     id: QueryId,
     key: Key,
@@ -73,11 +184,176 @@ impl Slot {
     }
 }
 
+/// The ways in which reading or writing a query can fail.
+///
+/// Each error records the offending query id and key. Errors which occur when reading a query also
+/// record `path`: the queries whose evaluation led to the failing read, outermost first. This is empty
+/// if the failing query was read directly by the user.
+///
+/// When a query function calls `QueryContext::get` and the read fails, the error unwinds through the query
+/// function and is returned by the nearest enclosing `try_get`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DipError {
+    /// `set` was called for a query that is not a registered input query.
+    NotAnInput { id: QueryId, key: Key },
+    /// An input query was read before its value was set.
+    InputNotSet {
+        id: QueryId,
+        key: Key,
+        path: Vec<(QueryId, Key)>,
+    },
+    /// A query was read which isn't registered with the database.
+    UnknownQuery {
+        id: QueryId,
+        key: Key,
+        path: Vec<(QueryId, Key)>,
+    },
+    /// The key or value type of a query does not match that of the query registered with the same id.
+    TypeMismatch {
+        id: QueryId,
+        key: Key,
+        path: Vec<(QueryId, Key)>,
+    },
+    /// A query (indirectly) read itself. `cycle` lists each query on the cycle, starting with the query
+    /// which was re-entered.
+    ///
+    /// Queries registered using `DatabaseBuilder::cycle_recovery` are evaluated to a fixpoint instead, unless
+    /// the cycle spans several threads. Such cycles are found when a thread would otherwise wait for a query
+    /// which is (indirectly) waiting for it. See `Database::snapshot`.
+    Cycle { cycle: Vec<(QueryId, Key)> },
+    /// A cycle-tolerant query was rerun `MAX_FIXPOINT_ITERATIONS` times without its value settling.
+    FixpointDidNotConverge {
+        id: QueryId,
+        key: Key,
+        path: Vec<(QueryId, Key)>,
+    },
+    /// `subscribe` or `accumulated` was called for a transparent query. These have no memo recording when their
+    /// value last changed, what they read, or what they accumulated.
+    Transparent { id: QueryId, key: Key },
+    /// A query was read using a `Snapshot` while an input was waiting to be changed. The snapshot's revision is
+    /// about to be out of date, so it should be dropped to let the change go ahead. See `Database::snapshot`.
+    Cancelled,
+}
+
+impl Display for DipError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = match self {
+            DipError::NotAnInput { id, .. } => return write!(f, "{} is not a valid input id", id),
+            DipError::InputNotSet { id, key, path } => {
+                write!(f, "input slot ({}, {:?}) has not been set", id, key)?;
+                path
+            }
+            DipError::UnknownQuery { id, key, path } => {
+                write!(f, "({}, {:?}) is not a registered query", id, key)?;
+                path
+            }
+            DipError::TypeMismatch { id, key, path } => {
+                write!(f, "key or value type mismatch for ({}, {:?})", id, key)?;
+                path
+            }
+            DipError::Cycle { cycle } => {
+                write!(f, "cycle detected: ")?;
+                for (id, key) in cycle {
+                    write!(f, "({}, {:?}) -> ", id, key)?;
+                }
+                let (id, key) = &cycle[0];
+                return write!(f, "({}, {:?})", id, key);
+            }
+            DipError::FixpointDidNotConverge { id, key, path } => {
+                write!(
+                    f,
+                    "({}, {:?}) did not converge after {} iterations",
+                    id, key, MAX_FIXPOINT_ITERATIONS
+                )?;
+                path
+            }
+            DipError::Transparent { id, key } => {
+                return write!(f, "({}, {:?}) is transparent, so has no memo", id, key)
+            }
+            DipError::Cancelled => return write!(f, "query cancelled as an input is being changed"),
+        };
+        for (i, (id, key)) in path.iter().enumerate() {
+            let separator = if i == 0 { ", read via " } else { " -> " };
+            write!(f, "{}({}, {:?})", separator, id, key)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DipError {}
+
+impl DipError {
+    /// Adds `prefix` to the start of the path of this error, if it has one.
+    fn prefix_path(mut self, prefix: &[(QueryId, Key)]) -> Self {
+        if let DipError::InputNotSet { path, .. }
+        | DipError::UnknownQuery { path, .. }
+        | DipError::TypeMismatch { path, .. }
+        | DipError::FixpointDidNotConverge { path, .. } = &mut self
+        {
+            path.splice(0..0, prefix.iter().cloned());
+        }
+        self
+    }
+}
+
+/// Why a read made while evaluating a query didn't produce a value.
+enum ReadError {
+    Failed(DipError),
+    /// The read needed to run a query function, but too many were already running. Every query being evaluated
+    /// is abandoned so that `Database::get_with_timestamp` can read this slot first. The path is the active query
+    /// path which led to the read, outermost first.
+    Deferred(Slot, Vec<(QueryId, Key)>),
+}
+
+impl From<DipError> for ReadError {
+    fn from(error: DipError) -> Self {
+        ReadError::Failed(error)
+    }
+}
+
+/// The panic payload used to unwind through a query function when one of its reads fails or is deferred.
+/// The reason is stashed in `Database::unwinding_error`.
+struct QueryFailed;
+
+/// How often the value of an input query is expected to change.
+///
+/// Inputs such as configuration or the contents of library files rarely change, and can be set with a
+/// high durability. The durability of a derived query is the lowest durability of any query it depends on.
+///
+/// The database tracks the last revision at which an input of each durability changed. If no input with
+/// at least the durability of a memo has changed since the memo was last verified then the memo must still
+/// be valid, and we can skip checking its dependencies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Durability {
+    Low,
+    Medium,
+    High,
+}
+
+impl Durability {
+    const COUNT: usize = 3;
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// A side channel through which query functions can report items such as warnings or diagnostics, in addition
+/// to their return values. See `QueryContext::accumulate` and `Database::accumulated`.
+///
+/// Like queries, users define a (typically empty) struct per accumulator and implement this trait for it.
+pub trait Accumulator: 'static {
+    /// The items reported using this accumulator.
+    type Item: Clone + Debug + Eq + Send + Sync + 'static;
+}
+
 /// The output of a query, together with the information needed to work out whether its value is still valid.
 #[derive(Debug, Clone)]
 struct Memo {
-    /// The output of the query.
-    value: Value,
+    /// The output of the query. This is `None` if the value has been discarded by `Database::sweep` or the
+    /// query is registered with `DatabaseBuilder::dependencies_only`, in which case the memo can still be
+    /// validated but the query must be rerun if its value is needed.
+    value: Option<Value>,
     /// When the user sets the value for an input query the database revision increases.
     ///
     /// This field tells us the most recent revision at which we validated the contents of this memo.
@@ -93,330 +369,2552 @@ struct Memo {
     ///
     /// If the values of any dependencies have changed since this memo was verified then the value in
     /// this memo is no longer valid and we need to recompute it to see if its value has changed.
-    dependencies: HashSet<Slot>,
+    ///
+    /// Dependencies are stored in the order in which the query first read them. When validating a memo we
+    /// check them in this order and stop at the first one which has changed. Later dependencies might not
+    /// be read at all when the query is rerun (for example if they're only read in one branch of an `if`),
+    /// so there's no point in bringing them up to date.
+    dependencies: Vec<Slot>,
+    /// For inputs, the durability the value was set with. For derived queries, the lowest durability of
+    /// any of the `dependencies`.
+    durability: Durability,
+    /// The items reported by the query function using `QueryContext::accumulate`, tagged with the type id of
+    /// their `Accumulator`. These are stored alongside the value, so reusing the memo reuses them too.
+    accumulated: Vec<(TypeId, Value)>,
 }
 
-/// A query output, together with the latest revision at which the output of this query changed.
+/// A query output, together with the latest revision at which the output of this query changed
+/// and its durability.
+#[derive(Clone)]
 struct StampedValue {
-    value: Value,
+    /// This is only `None` if the caller didn't need the value, and it had been discarded.
+    value: Option<Value>,
     changed_at: usize,
+    durability: Durability,
 }
 
 impl StampedValue {
-    fn new(value: Value, changed_at: usize) -> Self {
-        Self { value, changed_at }
+    fn new(value: Option<Value>, changed_at: usize, durability: Durability) -> Self {
+        Self {
+            value,
+            changed_at,
+            durability,
+        }
     }
 }
 
-/// Where everything happens.
-///
-/// A `Database` tracks the dependencies between queries, caches results, and contains
-/// the logic to determine when cached results need to be recomputed.
-pub struct Database {
-    /// The ids of inputs queries, i.e. those whose values will be set directly by the user
-    /// rather than computed from the values of other queries.
-    input_ids: Vec<QueryId>,
-    /// The functions used to compute the values for derived queries.
-    query_functions: HashMap<QueryId, Box<fn(&mut Database, Key) -> Value>>,
-    /// Cached query results, for both input and derived queries.
-    storage: HashMap<Slot, Memo>,
-    /// The database revision is updated every time the user sets a value for an input query.
-    revision: usize,
-    /// When running queries (or when checking whether a cached result is still valid), the
-    /// database will evaluate other queries.
-    ///
-    /// When evaluating a query we add this call (i.e. the (id, key) pair) to the top (i.e. last)
-    /// element in the `active_queries` stack to record the dependency, and then push a fresh
-    /// hash set onto the stack for the newly active query.
-    active_queries: Vec<HashSet<Slot>>,
-    /// Logs information about query execution to the console.
-    /// Run `cargo run --example walkthrough` to see example output.
-    logger: EventLogger,
-}
+/// The function used to compute the values of a derived query, with the key and value types of
+/// the query erased so that the functions for all queries can be stored in the same map.
+type QueryFunction = Arc<dyn Fn(&mut QueryContext, &Key) -> Value + Send + Sync>;
 
-// A helper macro to reduce the verbosity of event logging inside methods in `Database`.
-// You can safely ignore this macro, as well as all uses of it inside `Database`.
-macro_rules! event {
-    ($self:expr, $event:path) => {{
-        let event = $event;
-        $self.logger.log_event(&event)
-    }};
-    ($self:expr, $event:path, $($arg:expr),*) => {{
-        let event = $event($($arg.clone()),*);
-        $self.logger.log_event(&event)
-    }}
+/// Everything the `Database` knows about a registered query.
+struct QueryInfo {
+    kind: QueryKind,
+    /// The key and value types of the `Query`, used to catch type mismatches between queries
+    /// that share an id.
+    key_type: TypeId,
+    value_type: TypeId,
+    /// This is `None` unless the query is registered using `DatabaseBuilder::persist`.
+    codec: Option<Codec>,
 }
 
-impl Database {
-    /// `Database` needs to know about all the queries that it will be executing at construction.
-    pub fn new(
-        input_ids: Vec<QueryId>,
-        query_functions: HashMap<QueryId, Box<fn(&mut Database, Key) -> Value>>,
-    ) -> Database {
-        Database {
-            input_ids,
-            query_functions,
-            storage: HashMap::new(),
-            revision: 0,
-            active_queries: vec![],
-            logger: EventLogger::new(),
+impl QueryInfo {
+    fn new<Q: Query>(kind: QueryKind) -> Self {
+        QueryInfo {
+            kind,
+            key_type: TypeId::of::<Q::Key>(),
+            value_type: TypeId::of::<Q::Value>(),
+            codec: None,
         }
     }
 
-    /// Sets the user-provided value for an input query.
-    ///
-    /// The `IntoKey` bound is just to make this slightly more ergonomic - users can pass
-    /// `()` or an `i32` rather than needing to wrap these in a `Key` themselves.
-    pub fn set<K: Into<Key>>(&mut self, id: QueryId, key: K, value: Value) {
-        assert!(
-            self.input_ids.contains(&id),
-            "{} is not a valid input id",
-            id
-        );
+    fn matches<Q: Query>(&self) -> bool {
+        self.key_type == TypeId::of::<Q::Key>() && self.value_type == TypeId::of::<Q::Value>()
+    }
+}
 
-        // Storage is indexed by slots - a query call is identified by a query id
-        // and a key. Note that input queries also take a key, but a key of Key::Void
-        // may be used for (input or derived) queries which logically take no key values.
-        let slot = Slot::new(id, key.into());
+/// Converts the keys and values of a query to and from bytes, for `Database::save` and `Database::load`.
+struct Codec {
+    /// The names and `Persist::VERSION`s of the key and value types, which are saved to let `load` skip the memos
+    /// of queries whose types have changed. `TypeId`s can't be used for this as they differ between builds.
+    /// Type names aren't guaranteed to be stable either, but a name changing only means that memos are skipped
+    /// unnecessarily. Changing an encoding without changing the type's name is caught by the version.
+    key_type: (&'static str, u32),
+    value_type: (&'static str, u32),
+    write_key: fn(&Key, &mut Vec<u8>),
+    /// Reads a key from the whole of the given bytes.
+    read_key: fn(&[u8]) -> Option<Key>,
+    write_value: fn(&Value, &mut Vec<u8>),
+    /// Reads a value from the whole of the given bytes.
+    read_value: fn(&[u8]) -> Option<Value>,
+    /// Checks that a query is registered with the same key and value types as this codec.
+    matches: fn(&QueryInfo) -> bool,
+}
 
-        // As all query functions are pure, the only way for database state to change is
-        // in response to this method being called. Each time an input is set we update
-        // the database revision.
-        self.revision = self.revision + 1;
+impl Codec {
+    fn new<Q: Query>() -> Self
+    where
+        Q::Key: Persist,
+        Q::Value: Persist,
+    {
+        Codec {
+            key_type: (std::any::type_name::<Q::Key>(), Q::Key::VERSION),
+            value_type: (std::any::type_name::<Q::Value>(), Q::Value::VERSION),
+            write_key: |key, out| key.downcast_ref::<Q::Key>().unwrap().write(out),
+            read_key: |bytes| Some(Key::new(persist::read_exact::<Q::Key>(bytes)?)),
+            write_value: |value, out| value.downcast_ref::<Q::Value>().unwrap().write(out),
+            read_value: |bytes| Some(Value::new(persist::read_exact::<Q::Value>(bytes)?)),
+            matches: QueryInfo::matches::<Q>,
+        }
+    }
+}
 
-        event!(self, Event::Set, slot, value, self.revision);
+enum QueryKind {
+    /// Input queries, whose values are set explicitly by the user.
+    Input,
+    /// Derived queries, whose values are computed from other queries.
+    Derived(DerivedQuery),
+}
 
-        // If a memo exists and the new value is the same as the old value then don't
-        // update `changed_at`.
-        let changed_at = self.read_memo(slot)
-            .filter(|m| m.value == value)
-            .map(|m| m.changed_at)
-            .unwrap_or(self.revision);
+struct DerivedQuery {
+    function: QueryFunction,
+    /// The provisional value used when a cycle-tolerant query is re-entered, before any iterations have run.
+    /// This is `None` for queries which don't tolerate cycles.
+    cycle_initial: Option<Value>,
+    /// Volatile queries read state from outside the database, so are rerun whenever the revision changes.
+    volatile: bool,
+    /// The maximum number of memos to keep for this query, or `None` to keep them all.
+    lru_capacity: Option<usize>,
+    /// Whether to discard the values of this query's memos as soon as they have been returned.
+    dependencies_only: bool,
+    /// Transparent queries are never memoized. Their reads are recorded as dependencies of the calling query.
+    transparent: bool,
+    /// Whether to read the dependencies of this query's memos on worker threads when validating them.
+    parallel_validation: bool,
+}
 
-        // Input queries do not depend on any other queries, so their dependency sets are
-        // always empty.
-        let memo = Memo {
-            value,
-            verified_at: self.revision,
-            changed_at,
-            dependencies: HashSet::new(),
-        };
+/// The queries known to a `Database`. This is created by `DatabaseBuilder` and never modified afterwards.
+struct QueryRegistry {
+    queries: HashMap<QueryId, QueryInfo>,
+}
 
-        // Helper method that stores the memo in `self.storage` and emits an Event reporting this.
-        self.store_memo(slot, memo);
+impl QueryRegistry {
+    fn is_input(&self, id: QueryId) -> bool {
+        matches!(self.queries.get(id).map(|q| &q.kind), Some(QueryKind::Input))
     }
 
-    /// Computes or looks up the value for a query. This method is used for both input and derived queries.
-    pub fn get<K: Into<Key>>(&mut self, id: QueryId, key: K) -> Value {
-        self.get_with_timestamp(Slot::new(id, key.into())).value
+    fn derived(&self, id: QueryId) -> Option<&DerivedQuery> {
+        match self.queries.get(id).map(|q| &q.kind) {
+            Some(QueryKind::Derived(derived)) => Some(derived),
+            _ => None,
+        }
     }
+}
 
-    /// Computes or looks up the value for a query and returns the value along with the database revision
-    /// at which this value last changed.
-    fn get_with_timestamp(&mut self, slot: Slot) -> StampedValue {
-        event!(self, Event::Get, slot);
+/// The ways in which the queries registered with a `DatabaseBuilder` can be wired up incorrectly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistrationError {
+    /// The same id was registered as both an input and a derived query.
+    InputAndDerived(QueryId),
+    /// The same id was registered more than once as the same kind of query.
+    Duplicate(QueryId),
+    /// A setting was provided for a derived query, but no query function was registered for its id.
+    MissingQueryFunction(QueryId),
+    /// A query was marked to be persisted, but no query was registered with its id.
+    NotRegistered(QueryId),
+    /// A query was marked to be persisted, or given a cycle recovery value, using key or value types which don't
+    /// match those of the query registered with its id.
+    TypeMismatch(QueryId),
+    /// A derived query was given an LRU capacity of zero.
+    ZeroLruCapacity(QueryId),
+}
 
-        // If we called into this method as part of computing or validating the output for another query
-        // then record this call as a dependency of the parent query.
-        //
-        // When we store a `Memo` with the output of a query we read its dependencies from `active_queries`
-        // and store them in the memo.
-        if let Some(active) = self.active_queries.last_mut() {
-            active.insert(slot);
+impl Display for RegistrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistrationError::InputAndDerived(id) => {
+                write!(f, "{} is registered as both an input and a derived query", id)
+            }
+            RegistrationError::Duplicate(id) => write!(f, "{} is registered more than once", id),
+            RegistrationError::MissingQueryFunction(id) => {
+                write!(f, "no query function is registered for {}", id)
+            }
+            RegistrationError::NotRegistered(id) => write!(f, "{} is not a registered query", id),
+            RegistrationError::TypeMismatch(id) => {
+                write!(f, "{} is configured with different key or value types to its registration", id)
+            }
+            RegistrationError::ZeroLruCapacity(id) => write!(f, "{} has an LRU capacity of zero", id),
         }
-
-        // Make this the currently active query.
-        self.push_active_query();
-
-        // This `read` method could be inlined here. The only reason for not doing this is to remove the
-        // need to call `pop_active_query` at each early return location from that method.
-        let result = self.read(slot);
-
-        // Remove the top element of `active_queries` now that we're done with it.
-        self.pop_active_query();
-
-        result
     }
+}
 
-    /// The body of `get_with_timestamp` after recording this query as a dependency of the parent query (if any)
-    /// and pushing a new entry onto the active query stack.
-    fn read(&mut self, slot: Slot) -> StampedValue {
-        // Helper method that queries `self.storage` for a memo in this slot and emits an Event reporting this.
-        let memo = self.read_memo(slot);
-
-        if self.is_input_query(slot.id) {
-            // If this is an input query then we require the user to have provided a value via `.set(..)`.
-            let memo = memo.expect("attempting to query an input slot that has not been set");
-
-            event!(self, Event::MemoForInputQuery);
+impl std::error::Error for RegistrationError {}
 
-            // If this is the first read of this input at the current revision then update the memo to reflect this.
-            // Note that memoised values for inputs are always valid - they can't be invalidated by changes to the
-            // values of any other queries.
-            //
-            // Aside, short version:
-            //      We could have chosen to handle input queries in any of several other basically equivalent ways.
-            //
-            // Aside, longer version:
-            //      If you're wondering why we care about `verified_at` for inputs when we've just stated that input
-            //      `Memo`s are always valid, the answer is that it doesn't really matter either way.
-            //
-            //      The only significance of updating `verified_at` here is that it avoids the recursive call into 
-            //      `get_with_timestamp` inside the `has_changed_since` method below. This has no effect on the
-            //      the set of query functions that get run, but saves a bit of pushing to and popping from the active
-            //      query stack. We could also have chosen to special case inputs inside `has_changed_since`, or to
-            //      update `verified_at` for all inputs whenever a new value is set for _any_ input query, or made the
-            //      field optional and omitted it for inputs, or chosen from a range of yet other possibilities, without
-            //      changing the algorithm or calculations performed in any material way.
-            //
-            //      We could also have noted that `any_inputs_have_changed` as defined below would always be false
-            //      for inputs and that special casing inputs is not strictly necessary. But handling
-            //      inputs separately seemed slightly clearer.
-            //
-            if memo.verified_at != self.revision {
-                let new_memo = Memo {
-                    verified_at: self.revision,
-                    ..memo
-                };
-                self.store_memo(slot, new_memo);
-            }
+/// The ways in which `Database::load` can fail.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The file couldn't be read.
+    Io(io::Error),
+    /// The file wasn't written by `Database::save`, or was written using a different version of the file format.
+    UnsupportedFormat,
+    /// The file is truncated or corrupt.
+    Malformed,
+}
 
-            return StampedValue::new(memo.value, memo.changed_at);
+impl Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(error) => write!(f, "failed to read saved database: {}", error),
+            LoadError::UnsupportedFormat => write!(f, "not a saved database, or saved using a different format"),
+            LoadError::Malformed => write!(f, "saved database is truncated or corrupt"),
         }
+    }
+}
 
-        // If we have a memo and this isn't an input query then we need to check if the memoized value is still valid.
-        if let Some(memo) = memo.clone() {
-            // If we've verified the memo already at this revision then it must be usable.
-            if memo.verified_at == self.revision {
-                event!(self, Event::MemoVerifiedAtCurrentRevision);
-                return StampedValue::new(memo.value, memo.changed_at);
-            }
-
-            // Otherwise, we need to check the dependencies of the memo to see if any of their values have changed
-            // since the memo was last verified.
-            event!(self, Event::StartedInputChecks, memo.verified_at);
-
-            let any_inputs_have_changed = memo
-                .dependencies
-                .iter()
-                .any(|&input| self.has_changed_since(input, memo.verified_at));
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
 
-            event!(self, Event::CompletedInputChecks, any_inputs_have_changed);
+impl From<io::Error> for LoadError {
+    fn from(error: io::Error) -> Self {
+        LoadError::Io(error)
+    }
+}
 
-            // If the values used by when computing this memo have not changed this the memo is still valid
-            // and we can update the memo's `verified_at` field and return from this method.
-            //
-            // Otherwise we fall through to the code after this block that recomputes the memo using its query
-            // function.
-            if !any_inputs_have_changed {
-                let new_memo = Memo {
-                    verified_at: self.revision,
-                    ..memo
-                };
-                self.store_memo(slot, new_memo);
-                return StampedValue::new(memo.value, memo.changed_at);
-            }
-        }
+/// Registers the queries that a `Database` will evaluate, and creates the `Database`.
+///
+/// Mistakes in the registrations are reported by `build`.
+#[derive(Default)]
+pub struct DatabaseBuilder {
+    queries: Vec<(QueryId, QueryInfo)>,
+    settings: Vec<(QueryId, DerivedSetting)>,
+    persisted: Vec<(QueryId, Codec)>,
+}
 
-        // If we got to this point then either we don't have a memoised value or it's out of date.
-        // In either case we need to evaluate the query function.
-        let new_value = self.run_query_function(slot);
+/// Optional behaviours of derived queries, applied to their `DerivedQuery` by `DatabaseBuilder::build`.
+enum DerivedSetting {
+    /// The initial provisional value, and the type of the query it was provided for.
+    CycleRecovery(Value, TypeId),
+    Volatile,
+    LruCapacity(usize),
+    DependenciesOnly,
+    Transparent,
+    ParallelValidation,
+}
 
-        // Some logging.
-        if let Some(memo) = memo.clone() {
-            event!(self, Event::ValueComparison, memo.value, new_value, self.revision);
-        }
+impl DatabaseBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        // If we had a memo before and the query's value hasn't actually changed then
-        // we don't update `changed_at`.
-        let changed_at = memo
-            .filter(|m| m.value == new_value)
-            .map(|m| m.changed_at)
-            .unwrap_or(self.revision);
+    /// Registers an input query. The values of input queries are set by the user via `Database::set`.
+    pub fn add_input<Q: Query>(mut self) -> Self {
+        self.queries.push((Q::ID, QueryInfo::new::<Q>(QueryKind::Input)));
+        self
+    }
 
-        // Store the new memo, recording its dependencies by reading from the top element of from `active_queries`.
-        let memo = Memo {
-            value: new_value,
-            verified_at: self.revision,
-            changed_at,
-            dependencies: self.active_queries.last().unwrap().clone(),
+    /// Registers a derived query, along with the function used to compute its values.
+    ///
+    /// The function may be a closure which captures configuration, lookup tables or other shared state.
+    /// As with the values of input queries, anything captured must not change after registration -
+    /// the database assumes that query functions are pure.
+    pub fn add_derived<Q: Query>(
+        mut self,
+        function: impl Fn(&mut QueryContext, Q::Key) -> Q::Value + Send + Sync + 'static,
+    ) -> Self {
+        // The database checks key and value types against `QueryInfo` before running any query functions.
+        let function: QueryFunction = Arc::new(move |ctx, key| {
+            let key = key.downcast_ref::<Q::Key>().expect("Key type mismatch").clone();
+            Value::new(function(ctx, key))
+        });
+        let derived = DerivedQuery {
+            function,
+            cycle_initial: None,
+            volatile: false,
+            lru_capacity: None,
+            dependencies_only: false,
+            transparent: false,
+            parallel_validation: false,
         };
+        self.queries
+            .push((Q::ID, QueryInfo::new::<Q>(QueryKind::Derived(derived))));
+        self
+    }
 
-        self.store_memo(slot, memo);
-        StampedValue::new(new_value, changed_at)
-    }
-
-    /// Checks whether the output for a query has changed since the specified revision.
-    ///
-    /// If we have an up to date memo for this (query, key) pair then we can use the `changed_at` field
-    /// from the memo. Otherwise, we need to recurse into `get_with_timestamp` to get a `StampedValue`
-    /// for this slot.
-    ///
-    /// (Reminder of the state of the call stack if that happens:
-    ///     get_with_timestamp(query_one)
-    ///     -> read(query_one)
-    ///         -> has_changed_since(query_that_query_one_depends_on)
-    ///             -> get_with_timestamp(query_that_query_one_depends_on)
-    ///                 -> ...
-    /// )
-    fn has_changed_since(&mut self, slot: Slot, revision: usize) -> bool {
-        let changed_at = {
-            // If we _did_ have a mechanism for removing cached valued then we would return self.revision here if no memo existed.
-            let memo = self.storage.get(&slot).expect(
-                "previously queried values always exist as we never remove anything from our cache",
-            );
-
-            // If we've verified the memo this revision then we can trust its changed_at field.
-            if memo.verified_at == self.revision {
-                memo.changed_at
-            // If we've not verified the memo this revision then we need to recurse.
-            } else {
-                self.get_with_timestamp(slot).changed_at
-            }
-        };
-        event!(self, Event::ChangedAt, slot, changed_at);
-        changed_at > revision
+    /// Allows the derived query `Q` to (indirectly) read itself.
+    ///
+    /// If `Q` is re-entered while it is being evaluated then the inner read returns a provisional value
+    /// rather than failing with `DipError::Cycle`. The provisional value starts out as `initial`, and the
+    /// database reruns `Q` using the output of each run as the next provisional value until the output
+    /// stops changing.
+    pub fn cycle_recovery<Q: Query>(mut self, initial: Q::Value) -> Self {
+        let setting = DerivedSetting::CycleRecovery(Value::new(initial), TypeId::of::<Q::Value>());
+        self.settings.push((Q::ID, setting));
+        self
     }
 
-    /// Find the query function with id `slot.id` and run it. 
-    /// Recall that query functions have signature `fn(&mut Database, Key) -> Value`.
-    /// See `one_year_fee_query` in examples/walkthrough.rs for an example.
-    fn run_query_function(&mut self, slot: Slot) -> Value {
-        event!(self, Event::StartedQueryEvaluation);
-        let query = self
-            .query_functions
-            .get(slot.id)
-            .expect("Missing query function")
-            .clone();
-        let new_value = query(self, slot.key);
-        event!(self, Event::CompletedQueryEvaluation);
-        new_value
+    /// Marks the derived query `Q` as volatile, i.e. as reading something outside of the database such as the
+    /// current time.
+    ///
+    /// The memo for a volatile query is only trusted during the revision in which it was computed. After that
+    /// the query is rerun whenever it is read, and queries which depend on it must always check their dependencies.
+    /// If rerunning a volatile query produces the same value as before then its dependents still aren't rerun.
+    pub fn volatile<Q: Query>(mut self) -> Self {
+        self.settings.push((Q::ID, DerivedSetting::Volatile));
+        self
     }
 
-    fn push_active_query(&mut self) {
-        event!(self, Event::PushActiveQuery);
-        self.active_queries.push(HashSet::new());
+    /// Limits the number of memos kept for the derived query `Q`. Once `capacity` memos are stored, storing
+    /// another evicts the least recently used. The capacity must be at least one.
+    ///
+    /// Evicted values are recomputed if they're read again. A query which depended on an evicted memo treats it as
+    /// changed, so will be rerun the next time it is read at a new revision.
+    pub fn lru_capacity<Q: Query>(mut self, capacity: usize) -> Self {
+        self.settings.push((Q::ID, DerivedSetting::LruCapacity(capacity)));
+        self
     }
 
-    fn pop_active_query(&mut self) -> Option<HashSet<Slot>> {
-        event!(self, Event::PopActiveQuery);
-        self.active_queries.pop()
+    /// Stores only the dependencies and timestamps of the derived query `Q`, discarding each value once it has
+    /// been returned. This is useful for queries whose values are large but cheap to recompute.
+    ///
+    /// Queries which depend on `Q` can still be validated without rerunning `Q`, but reading `Q` itself always
+    /// reruns its query function. As there's no previous value to compare against, rerunning `Q` after one of its
+    /// dependencies has changed always counts as a change to its value.
+    pub fn dependencies_only<Q: Query>(mut self) -> Self {
+        self.settings.push((Q::ID, DerivedSetting::DependenciesOnly));
+        self
     }
 
-    fn store_memo(&mut self, slot: Slot, memo: Memo) {
-        // The read of the existing memo value here is solely to let us generate more helpful logs.
-        let old_memo = self.storage.get(&slot).cloned();
-        event!(self, Event::StoreMemo, old_memo, memo);
-        self.storage.insert(slot, memo);
+    /// Marks the derived query `Q` as transparent. Transparent queries are never memoized - their query function
+    /// is run every time they're read, and the queries it reads are recorded as dependencies of the calling query
+    /// instead. This is useful for trivial wrappers around other queries, where storing and validating a memo
+    /// would cost more than rerunning the function.
+    ///
+    /// Transparent queries ignore any other settings.
+    pub fn transparent<Q: Query>(mut self) -> Self {
+        self.settings.push((Q::ID, DerivedSetting::Transparent));
+        self
     }
 
-    fn read_memo(&mut self, slot: Slot) -> Option<Memo> {
-        let value = self.storage.get(&slot).cloned();
-        event!(self, Event::ReadMemo, value);
-        value
+    /// Validates the memos of the derived query `Q` by reading their dependencies in parallel, on worker threads
+    /// started for each memo validated. This is useful for queries with many dependencies which are slow to
+    /// validate, such as a query over every file in a project, but isn't worth the cost of starting the threads
+    /// for queries with only a few.
+    ///
+    /// Each worker reads a contiguous block of the dependencies in order, and as soon as a dependency is found to
+    /// have changed, the reads of later dependencies are cancelled. Workers are only used when validation starts
+    /// outside of any query function, as the workers can't see the queries running on the calling thread.
+    pub fn parallel_validation<Q: Query>(mut self) -> Self {
+        self.settings.push((Q::ID, DerivedSetting::ParallelValidation));
+        self
+    }
+
+    /// Marks the input or derived query `Q` to be persisted. Its memos are written by `Database::save` and can be
+    /// restored by `Database::load`, so that they don't need to be recomputed by a new process.
+    ///
+    /// A memo is only saved if every query it depends on is also persisted.
+    pub fn persist<Q: Query>(mut self) -> Self
+    where
+        Q::Key: Persist,
+        Q::Value: Persist,
+    {
+        self.persisted.push((Q::ID, Codec::new::<Q>()));
+        self
     }
 
-    fn is_input_query(&self, id: QueryId) -> bool {
-        self.input_ids.contains(&id)
+    /// Checks that every query id is registered exactly once, and that every setting refers to a
+    /// registered derived query with matching types, and returns a `Database` using these queries.
+    pub fn build(self) -> Result<Database, RegistrationError> {
+        let mut queries = HashMap::<QueryId, QueryInfo>::new();
+        for (id, info) in self.queries {
+            if let Some(existing) = queries.get(id) {
+                let existing_is_input = matches!(existing.kind, QueryKind::Input);
+                return Err(match existing_is_input == matches!(info.kind, QueryKind::Input) {
+                    true => RegistrationError::Duplicate(id),
+                    false => RegistrationError::InputAndDerived(id),
+                });
+            }
+            queries.insert(id, info);
+        }
+
+        for (id, setting) in self.settings {
+            let (value_type, derived) = match queries.get_mut(id) {
+                Some(QueryInfo {
+                    kind: QueryKind::Derived(derived),
+                    value_type,
+                    ..
+                }) => (*value_type, derived),
+                _ => return Err(RegistrationError::MissingQueryFunction(id)),
+            };
+            match setting {
+                // The initial value is returned when the query is re-entered, so must have the query's value type.
+                DerivedSetting::CycleRecovery(_, initial_type) if initial_type != value_type => {
+                    return Err(RegistrationError::TypeMismatch(id))
+                }
+                DerivedSetting::CycleRecovery(initial, _) => derived.cycle_initial = Some(initial),
+                DerivedSetting::Volatile => derived.volatile = true,
+                // Every memo would be evicted as soon as it was stored, so no query would ever find one.
+                DerivedSetting::LruCapacity(0) => return Err(RegistrationError::ZeroLruCapacity(id)),
+                DerivedSetting::LruCapacity(capacity) => derived.lru_capacity = Some(capacity),
+                DerivedSetting::DependenciesOnly => derived.dependencies_only = true,
+                DerivedSetting::Transparent => derived.transparent = true,
+                DerivedSetting::ParallelValidation => derived.parallel_validation = true,
+            }
+        }
+
+        for (id, codec) in self.persisted {
+            let info = queries
+                .get_mut(id)
+                .ok_or(RegistrationError::NotRegistered(id))?;
+            if !(codec.matches)(info) {
+                return Err(RegistrationError::TypeMismatch(id));
+            }
+            info.codec = Some(codec);
+        }
+
+        Ok(Database::new(QueryRegistry { queries }))
+    }
+}
+
+/// The keys of the stored memos for a derived query with an LRU capacity, ordered by when they were last used.
+struct Lru {
+    capacity: usize,
+    /// Incremented each time a memo is used.
+    clock: usize,
+    last_used: HashMap<Key, usize>,
+    by_last_used: BTreeMap<usize, Key>,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Self {
+        Lru {
+            capacity,
+            clock: 0,
+            last_used: HashMap::new(),
+            by_last_used: BTreeMap::new(),
+        }
+    }
+
+    /// Records that the memo for `key` has been used, and returns the keys of any memos which need
+    /// evicting to stay within capacity.
+    fn touch(&mut self, key: &Key) -> Vec<Key> {
+        self.remove(key);
+        self.clock += 1;
+        self.last_used.insert(key.clone(), self.clock);
+        self.by_last_used.insert(self.clock, key.clone());
+
+        let mut evicted = vec![];
+        while self.last_used.len() > self.capacity {
+            let (_, key) = self.by_last_used.pop_first().unwrap();
+            self.last_used.remove(&key);
+            evicted.push(key);
+        }
+        evicted
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(last_used) = self.last_used.remove(key) {
+            self.by_last_used.remove(&last_used);
+        }
+    }
+}
+
+/// Cached query results, for both input and derived queries, along with the indexes used to manage them.
+///
+/// All insertions into and removals from `storage` go through the methods here, to keep `lru` and `dependents`
+/// up to date.
+struct MemoTable {
+    storage: HashMap<Slot, Memo>,
+    /// Tracks memo usage for the derived queries registered with an LRU capacity.
+    lru: HashMap<QueryId, Lru>,
+    /// The reverse of the `dependencies` of each memo in `storage`, i.e. the slots whose memos read each slot.
+    dependents: HashMap<Slot, HashSet<Slot>>,
+}
+
+impl MemoTable {
+    fn new(registry: &QueryRegistry) -> Self {
+        let lru = registry
+            .queries
+            .iter()
+            .filter_map(|(id, query)| match &query.kind {
+                QueryKind::Derived(derived) => Some((*id, Lru::new(derived.lru_capacity?))),
+                QueryKind::Input => None,
+            })
+            .collect();
+        MemoTable {
+            storage: HashMap::new(),
+            lru,
+            dependents: HashMap::new(),
+        }
+    }
+
+    /// Stores the memo for `slot` and marks it as recently used. Returns the slots of any memos evicted as a result.
+    fn insert(&mut self, slot: Slot, memo: Memo) -> Vec<Slot> {
+        let dependencies = memo.dependencies.clone();
+        let old_memo = self.storage.insert(slot.clone(), memo);
+        let old_dependencies = old_memo.map_or(vec![], |m| m.dependencies);
+        // Revalidating a memo doesn't change its dependencies.
+        if old_dependencies != dependencies {
+            self.unlink_dependencies(&slot, &old_dependencies);
+            for dependency in dependencies {
+                self.dependents.entry(dependency).or_default().insert(slot.clone());
+            }
+        }
+        self.touch(&slot)
+    }
+
+    fn remove(&mut self, slot: &Slot) -> Option<Memo> {
+        if let Some(lru) = self.lru.get_mut(slot.id) {
+            lru.remove(&slot.key);
+        }
+        let memo = self.storage.remove(slot)?;
+        self.unlink_dependencies(slot, &memo.dependencies);
+        Some(memo)
+    }
+
+    /// Marks the stored memo for `slot` as recently used, evicting other memos for the same query if this
+    /// takes it over its LRU capacity. Returns the slots of the evicted memos.
+    fn touch(&mut self, slot: &Slot) -> Vec<Slot> {
+        let evicted = match self.lru.get_mut(slot.id) {
+            Some(lru) => lru.touch(&slot.key),
+            None => return vec![],
+        };
+        evicted
+            .into_iter()
+            .map(|key| {
+                let slot = Slot::new(slot.id, key);
+                self.remove(&slot);
+                slot
+            })
+            .collect()
+    }
+
+    fn unlink_dependencies(&mut self, slot: &Slot, dependencies: &[Slot]) {
+        for dependency in dependencies {
+            if let Some(dependents) = self.dependents.get_mut(dependency) {
+                dependents.remove(slot);
+                if dependents.is_empty() {
+                    self.dependents.remove(dependency);
+                }
+            }
+        }
+    }
+}
+
+/// The queries whose functions are currently being run by a `Database` or one of its `Snapshot`s.
+/// See `Database::claim`.
+struct InProgress {
+    /// The handle (i.e. database or snapshot) running the query function for each slot.
+    owners: HashMap<Slot, usize>,
+    /// For each handle which is blocked waiting for another handle to finish running a query function, the
+    /// slot it's waiting for and its active query path at the time it started waiting.
+    waiting: HashMap<usize, (Slot, Vec<(QueryId, Key)>)>,
+}
+
+/// The parts of a `Database` which are shared with its `Snapshot`s.
+struct Shared {
+    /// The input and derived queries, and the functions used to compute the values for derived queries.
+    registry: QueryRegistry,
+    /// The lock is only held while reading or writing memos, never while running a query function.
+    memos: Mutex<MemoTable>,
+    /// The number of `Snapshot`s which haven't yet been dropped.
+    snapshots: Mutex<usize>,
+    /// Set while a write is waiting for snapshots to be dropped, to cancel the queries running on them.
+    cancelled: AtomicBool,
+    /// Notified whenever a `Snapshot` is dropped.
+    snapshot_dropped: Condvar,
+    in_progress: Mutex<InProgress>,
+    /// Notified whenever a query function finishes running, so that any handles waiting for it can continue.
+    query_finished: Condvar,
+    /// Used to give each handle a distinct id.
+    next_handle: AtomicUsize,
+    /// The most worker threads to use when validating dependencies in parallel. This is the available
+    /// parallelism, except in tests, which use several workers even on machines with a single core.
+    validation_workers: usize,
+}
+
+impl Shared {
+    fn memos(&self) -> MutexGuard<'_, MemoTable> {
+        self.memos.lock().unwrap()
+    }
+}
+
+/// The first bytes of every file written by `Database::save`.
+const SAVE_FILE_MAGIC: &[u8] = b"dip\0";
+
+/// The version of the format used by `Database::save`. This must be increased whenever the format changes.
+const SAVE_FILE_VERSION: u32 = 2;
+
+/// The maximum number of times a cycle-tolerant query is rerun while searching for a fixpoint.
+pub const MAX_FIXPOINT_ITERATIONS: usize = 100;
+
+/// The maximum number of query functions running at once on a thread, as each one uses a few kilobytes of the
+/// thread's stack. See `Database::get_with_timestamp`.
+const MAX_NESTED_QUERY_FUNCTIONS: usize = 100;
+
+/// What `Database::drive` needs to do next. See `Database::get_with_timestamp` for details.
+enum Step {
+    /// Read a dependency of the memo being validated by the query at the top of the active query stack.
+    Read(Slot),
+    /// Continue checking the dependencies of the memo being validated by the query at the top of the stack.
+    CheckDependencies,
+    /// Run the query function for the query at the top of the stack, given its existing memo (if any) and
+    /// whether that memo is valid. See `Database::recompute`.
+    ///
+    /// Query functions can read other queries, so running them from the loop in `Database::drive` rather than
+    /// from the methods which decide to run them keeps the stack used by each nested query function small.
+    Recompute(Option<Memo>, bool),
+    /// A read has finished, and its query has been removed from the stack.
+    Finished(Result<StampedValue, ReadError>),
+}
+
+/// A query which is currently being evaluated, or whose memo is currently being validated.
+struct ActiveQuery {
+    slot: Slot,
+    /// The other queries read so far while evaluating or validating `slot`, in the order they were first read.
+    dependencies: Vec<Slot>,
+    /// The same slots as `dependencies`, to allow efficiently checking whether a slot has already been read.
+    dependency_set: HashSet<Slot>,
+    /// The lowest durability of any of the `dependencies`.
+    durability: Durability,
+    /// The items accumulated so far while evaluating `slot`. See `QueryContext::accumulate`.
+    accumulated: Vec<(TypeId, Value)>,
+    /// For cycle-tolerant queries, the value returned if this query is re-entered.
+    provisional_value: Option<Value>,
+    /// The index in `active_queries` of the innermost cycle-tolerant query at or below this one, if any.
+    innermost_cycle_tolerant: Option<usize>,
+    /// Whether `provisional_value` has been read during the current fixpoint iteration.
+    provisional_value_read: bool,
+    /// For cycle-tolerant queries, the previous contents of every slot whose memo has been stored since this
+    /// query became active. These memos may have been computed from a provisional value, so we restore the
+    /// previous contents before each fixpoint iteration and if evaluation fails.
+    overwritten_memos: HashMap<Slot, Option<Memo>>,
+    /// Whether the caller needs the value of `slot`, or only the revision at which it last changed.
+    need_value: bool,
+    /// While checking the dependencies of an existing memo for `slot`, the memo and the index of the next
+    /// dependency to check.
+    validating: Option<(Memo, usize)>,
+    /// Whether we've claimed `slot` in order to run its query function. See `Database::claim`.
+    claimed: bool,
+}
+
+impl ActiveQuery {
+    fn new(slot: Slot, provisional_value: Option<Value>, innermost_cycle_tolerant: Option<usize>) -> Self {
+        Self {
+            slot,
+            dependencies: vec![],
+            dependency_set: HashSet::new(),
+            durability: Durability::High,
+            accumulated: vec![],
+            provisional_value,
+            innermost_cycle_tolerant,
+            provisional_value_read: false,
+            overwritten_memos: HashMap::new(),
+            need_value: true,
+            validating: None,
+            claimed: false,
+        }
+    }
+
+    fn add_dependency(&mut self, slot: Slot) {
+        if self.dependency_set.insert(slot.clone()) {
+            self.dependencies.push(slot);
+        }
+    }
+
+    /// Forgets the dependencies, durability and accumulated items recorded so far.
+    fn clear_recorded(&mut self) {
+        self.dependencies.clear();
+        self.dependency_set.clear();
+        self.durability = Durability::High;
+        self.accumulated.clear();
+    }
+}
+
+/// Where everything happens.
+///
+/// A `Database` tracks the dependencies between queries, caches results, and contains
+/// the logic to determine when cached results need to be recomputed.
+///
+/// Queries can also be evaluated on other threads using `Snapshot`s of the database. See `snapshot`.
+pub struct Database {
+    /// The query registry and memos, shared with any `Snapshot`s of this database.
+    shared: Arc<Shared>,
+    /// Distinguishes this database from its snapshots. See `claim`.
+    handle: usize,
+    /// For the handles used by the worker threads which validate dependencies in parallel, set once the
+    /// dependencies left for the worker come after one which has changed. Workers don't start workers of their
+    /// own. See `dependencies_changed_on_worker_threads`.
+    worker_cancelled: Option<Arc<AtomicBool>>,
+    /// The database revision is updated every time the user sets a value for an input query.
+    revision: usize,
+    /// The last revision at which an input with at least each durability changed, indexed by `Durability::index`.
+    last_changed: [usize; Durability::COUNT],
+    /// Callbacks to run when the values of queries change. See `subscribe`.
+    subscriptions: Vec<Subscription>,
+    next_subscription_id: usize,
+    /// When running queries (or when checking whether a cached result is still valid), the
+    /// database will evaluate other queries.
+    ///
+    /// When evaluating a query we add this call (i.e. the (id, key) pair) to the dependencies of the
+    /// top (i.e. last) element in the `active_queries` stack, and then push a fresh entry onto the stack
+    /// for the newly active query.
+    active_queries: Vec<ActiveQuery>,
+    /// The index in `active_queries` of each active query, so that we can tell whether a query is active without
+    /// searching the whole stack.
+    active_indexes: HashMap<Slot, usize>,
+    /// Memos stored while a cycle-tolerant query is active. These may have been computed from provisional values,
+    /// so aren't shared with other threads until the outermost cycle-tolerant query has finished.
+    provisional_memos: HashMap<Slot, Memo>,
+    /// The number of query functions currently running. See `get_with_timestamp`.
+    query_function_depth: usize,
+    /// The results of the reads deferred by `get_with_timestamp`, for use while retrying the read which deferred
+    /// them.
+    deferred_values: HashMap<Slot, StampedValue>,
+    /// The reason the currently unwinding query function failed, if any.
+    /// See `QueryContext::get` and `Database::run_query_function`.
+    unwinding_error: Option<ReadError>,
+    /// Logs information about query execution to the console.
+    /// Run `cargo run --example walkthrough` to see example output.
+    logger: EventLogger,
+}
+
+/// Identifies a callback registered using `Database::subscribe`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(usize);
+
+type SubscriptionCallback = Box<dyn FnMut(&Value) + Send + Sync>;
+
+struct Subscription {
+    id: SubscriptionId,
+    slot: Slot,
+    /// The revision at which the subscriber last saw the value of `slot`. If the value has changed since then
+    /// we need to run the callback.
+    seen_at: usize,
+    callback: SubscriptionCallback,
+}
+
+// A helper macro to reduce the verbosity of event logging inside methods in `Database`.
+// You can safely ignore this macro, as well as all uses of it inside `Database`.
+macro_rules! event {
+    ($self:expr, $event:path) => {{
+        let event = $event;
+        $self.logger.log_event(&event)
+    }};
+    ($self:expr, $event:path, $($arg:expr),*) => {{
+        let event = $event($($arg.clone()),*);
+        $self.logger.log_event(&event)
+    }}
+}
+
+impl Database {
+    /// `Database` needs to know about all the queries that it will be executing at construction.
+    /// Use `DatabaseBuilder` to register these.
+    fn new(registry: QueryRegistry) -> Database {
+        let memos = MemoTable::new(&registry);
+        let shared = Shared {
+            registry,
+            memos: Mutex::new(memos),
+            snapshots: Mutex::new(0),
+            cancelled: AtomicBool::new(false),
+            snapshot_dropped: Condvar::new(),
+            in_progress: Mutex::new(InProgress {
+                owners: HashMap::new(),
+                waiting: HashMap::new(),
+            }),
+            query_finished: Condvar::new(),
+            next_handle: AtomicUsize::new(0),
+            validation_workers: thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+        Database::from_shared(Arc::new(shared), 0, [0; Durability::COUNT])
+    }
+
+    fn from_shared(shared: Arc<Shared>, revision: usize, last_changed: [usize; Durability::COUNT]) -> Database {
+        let handle = shared.next_handle.fetch_add(1, Ordering::SeqCst);
+        Database {
+            shared,
+            handle,
+            worker_cancelled: None,
+            revision,
+            last_changed,
+            subscriptions: vec![],
+            next_subscription_id: 0,
+            active_queries: vec![],
+            active_indexes: HashMap::new(),
+            provisional_memos: HashMap::new(),
+            query_function_depth: 0,
+            deferred_values: HashMap::new(),
+            unwinding_error: None,
+            logger: EventLogger::new(),
+        }
+    }
+
+    /// Sets the user-provided value for an input query, with `Durability::Low`. Like every method which
+    /// changes an input, this blocks until all `Snapshot`s of the database have been dropped.
+    ///
+    /// Panics if `Q` is not an input query. See `try_set` for a non-panicking version of this method.
+    pub fn set<Q: Query>(&mut self, key: Q::Key, value: Q::Value) {
+        self.set_with_durability::<Q>(key, value, Durability::Low)
+    }
+
+    /// Sets the user-provided value for an input query, with `Durability::Low`, or returns an error if `Q` is
+    /// not an input query.
+    pub fn try_set<Q: Query>(&mut self, key: Q::Key, value: Q::Value) -> Result<(), DipError> {
+        self.try_set_with_durability::<Q>(key, value, Durability::Low)
+    }
+
+    /// Sets the user-provided value for an input query, with the given durability.
+    ///
+    /// Panics if `Q` is not an input query.
+    pub fn set_with_durability<Q: Query>(&mut self, key: Q::Key, value: Q::Value, durability: Durability) {
+        self.try_set_with_durability::<Q>(key, value, durability)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Sets the user-provided value for an input query, with the given durability, or returns an error if `Q`
+    /// is not an input query.
+    pub fn try_set_with_durability<Q: Query>(
+        &mut self,
+        key: Q::Key,
+        value: Q::Value,
+        durability: Durability,
+    ) -> Result<(), DipError> {
+        // Storage is indexed by slots - a query call is identified by a query id
+        // and a key. Note that input queries also take a key, but a key of `()`
+        // may be used for (input or derived) queries which logically take no key values.
+        let slot = Slot::new(Q::ID, Key::new(key));
+        self.check_input::<Q>(&slot)?;
+
+        // As all query functions are pure, the only way for database state to change is
+        // in response to this method being called. Each time an input is set we update
+        // the database revision.
+        //
+        // Snapshots are pinned to the current revision, so first we wait for them to go away.
+        self.wait_for_snapshots();
+        self.revision += 1;
+
+        self.write_input(slot, Value::new(value), durability);
+        Ok(())
+    }
+
+    /// Removes the user-provided value for an input query. Later reads of this input fail with
+    /// `DipError::InputNotSet` until a new value is set, and any memo which depends on it is treated as out of date.
+    ///
+    /// Panics if `Q` is not an input query. See `try_remove` for a non-panicking version of this method.
+    pub fn remove<Q: Query>(&mut self, key: Q::Key) {
+        self.try_remove::<Q>(key)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Removes the user-provided value for an input query, or returns an error if `Q` is not an input query.
+    pub fn try_remove<Q: Query>(&mut self, key: Q::Key) -> Result<(), DipError> {
+        let slot = Slot::new(Q::ID, Key::new(key));
+        self.check_input::<Q>(&slot)?;
+
+        // Removing a value which was never set doesn't change anything, so doesn't need a new revision.
+        if self.shared.memos().storage.contains_key(&slot) {
+            self.wait_for_snapshots();
+            self.revision += 1;
+            self.remove_input(slot);
+        }
+        Ok(())
+    }
+
+    /// Sets the values of several input queries at once. All of the writes made using the `InputBatch` passed to
+    /// `f` share a single new revision, so no query can observe some but not all of them.
+    ///
+    /// Setting 10,000 inputs one at a time creates 10,000 revisions, and validating a memo which was last
+    /// verified before them all might have to walk through many of them. Setting them in a batch creates one.
+    /// A batch which doesn't set or remove any values doesn't create a revision at all.
+    pub fn set_many(&mut self, f: impl FnOnce(&mut InputBatch)) {
+        f(&mut InputBatch {
+            db: self,
+            new_revision: false,
+        });
+    }
+
+    /// The current database revision. This increases every time an input is set or removed.
+    pub fn revision(&self) -> usize {
+        self.revision
+    }
+
+    /// Returns a read-only view of the database at the current revision, which can be sent to another thread.
+    ///
+    /// Queries can be evaluated on several snapshots in parallel. Any memo computed using a snapshot is shared
+    /// with the database and with all other snapshots.
+    ///
+    /// Setting or removing an input cancels the queries running on snapshots, and then blocks until every
+    /// snapshot has been dropped. Once cancelled, every read using a snapshot fails with `DipError::Cancelled`,
+    /// including reads made by query functions which are already running. The snapshot should then be dropped.
+    /// A thread must not hold onto a snapshot while changing inputs itself.
+    ///
+    /// If a query function is already running on another snapshot (or on the database) then we wait for it to
+    /// finish and use its result, rather than running it again.
+    pub fn snapshot(&self) -> Snapshot {
+        *self.shared.snapshots.lock().unwrap() += 1;
+        Snapshot {
+            db: Database::from_shared(self.shared.clone(), self.revision, self.last_changed),
+        }
+    }
+
+    /// Cancels the queries running on any `Snapshot`s of this database, and blocks until they have all been dropped.
+    fn wait_for_snapshots(&self) {
+        let mut snapshots = self.shared.snapshots.lock().unwrap();
+        if *snapshots == 0 {
+            return;
+        }
+        self.shared.cancelled.store(true, Ordering::SeqCst);
+        while *snapshots > 0 {
+            snapshots = self.shared.snapshot_dropped.wait(snapshots).unwrap();
+        }
+        // No snapshots remain to observe the flag, and no new ones can be created until the write has finished.
+        self.shared.cancelled.store(false, Ordering::SeqCst);
+    }
+
+    /// Discards derived memos, or just their values, to reduce memory use. See `Sweep` for how to choose which
+    /// memos are discarded.
+    ///
+    /// The memos for input queries are never discarded. Any memos which remain can still be validated: if a
+    /// memo's dependency has been discarded then the dependency is treated as having changed.
+    pub fn sweep(&mut self, sweep: Sweep) {
+        let reachable = sweep.roots.as_ref().map(|roots| self.reachable_from(roots));
+        let mut memos = self.shared.memos();
+        let discard: Vec<Slot> = memos
+            .storage
+            .iter()
+            .filter(|(slot, memo)| {
+                let unverified = sweep.unverified_since.is_some_and(|r| memo.verified_at < r);
+                let unreachable = reachable.as_ref().is_some_and(|r| !r.contains(*slot));
+                !self.shared.registry.is_input(slot.id) && (unverified || unreachable)
+            })
+            .map(|(slot, _)| slot.clone())
+            .collect();
+
+        event!(self, Event::Sweep, discard.len(), sweep.values_only);
+        for slot in discard {
+            if sweep.values_only {
+                memos.storage.get_mut(&slot).unwrap().value = None;
+            } else {
+                memos.remove(&slot);
+            }
+        }
+    }
+
+    /// Returns every slot with a memo that can be reached from `roots` by following memo dependencies.
+    fn reachable_from(&self, roots: &[Slot]) -> HashSet<Slot> {
+        let memos = self.shared.memos();
+        let mut reachable = HashSet::new();
+        let mut stack = roots.to_vec();
+        while let Some(slot) = stack.pop() {
+            if let Some(memo) = memos.storage.get(&slot) {
+                if reachable.insert(slot) {
+                    stack.extend(memo.dependencies.iter().cloned());
+                }
+            }
+        }
+        reachable
+    }
+
+    /// Returns the queries whose memos depend directly on the query `Q` with key `key`, in no particular order.
+    ///
+    /// These memos may have been computed at an earlier revision, and not all of them will necessarily read
+    /// `Q` if rerun. They're the memos which would need checking if the value of `Q` changed.
+    pub fn dependents_of<Q: Query>(&self, key: Q::Key) -> Vec<(QueryId, Key)> {
+        let slot = Slot::new(Q::ID, Key::new(key));
+        self.shared
+            .memos()
+            .dependents
+            .get(&slot)
+            .into_iter()
+            .flatten()
+            .map(|d| (d.id, d.key.clone()))
+            .collect()
+    }
+
+    /// Returns the queries whose memos depend directly or indirectly on the query `Q` with key `key`, in no
+    /// particular order. See `dependents_of`.
+    pub fn transitive_dependents_of<Q: Query>(&self, key: Q::Key) -> Vec<(QueryId, Key)> {
+        let memos = self.shared.memos();
+        let mut visited = HashSet::new();
+        let mut stack = vec![Slot::new(Q::ID, Key::new(key))];
+        while let Some(slot) = stack.pop() {
+            for dependent in memos.dependents.get(&slot).into_iter().flatten() {
+                if visited.insert(dependent.clone()) {
+                    stack.push(dependent.clone());
+                }
+            }
+        }
+        visited.into_iter().map(|d| (d.id, d.key)).collect()
+    }
+
+    /// Writes the current revision and the memos for every query registered using `DatabaseBuilder::persist` to
+    /// a file, so that a later process can restore them using `load`.
+    ///
+    /// Memos are skipped if any of their dependencies aren't persisted, or if they have accumulated items. These
+    /// queries are rerun when they're next read after loading.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let registry = &self.shared.registry;
+        let codec = |slot: &Slot| registry.queries[slot.id].codec.as_ref();
+        let write_slot = |slot: &Slot, out: &mut Vec<u8>| {
+            let mut key = vec![];
+            (codec(slot).unwrap().write_key)(&slot.key, &mut key);
+            (slot.id.to_string(), key).write(out);
+        };
+
+        let mut out = SAVE_FILE_MAGIC.to_vec();
+        SAVE_FILE_VERSION.write(&mut out);
+        self.revision.write(&mut out);
+        self.last_changed.to_vec().write(&mut out);
+
+        let persisted: Vec<_> = registry
+            .queries
+            .iter()
+            .filter_map(|(id, info)| Some((id, info.codec.as_ref()?)))
+            .collect();
+        persisted.len().write(&mut out);
+        for (id, codec) in persisted {
+            let (key_type, value_type) = (codec.key_type, codec.value_type);
+            let types = ((key_type.0.to_string(), key_type.1), (value_type.0.to_string(), value_type.1));
+            (id.to_string(), types).write(&mut out);
+        }
+
+        let memos = self.shared.memos();
+        let saved: Vec<_> = memos
+            .storage
+            .iter()
+            .filter(|(slot, memo)| {
+                codec(slot).is_some()
+                    && memo.accumulated.is_empty()
+                    && memo.dependencies.iter().all(|d| codec(d).is_some())
+            })
+            .collect();
+        saved.len().write(&mut out);
+        for (slot, memo) in saved {
+            write_slot(slot, &mut out);
+            let value = memo.value.as_ref().map(|value| {
+                let mut bytes = vec![];
+                (codec(slot).unwrap().write_value)(value, &mut bytes);
+                bytes
+            });
+            value.write(&mut out);
+            (memo.verified_at, memo.changed_at, memo.durability).write(&mut out);
+            memo.dependencies.len().write(&mut out);
+            for dependency in &memo.dependencies {
+                write_slot(dependency, &mut out);
+            }
+        }
+        drop(memos);
+
+        fs::write(path, out)
+    }
+
+    /// Replaces the memos of this database with those written by `save`, so that queries which haven't changed
+    /// don't need to be rerun. This is intended to be called on a newly built database, with the same queries as
+    /// the database that was saved.
+    ///
+    /// The database moves to the revision after the saved one, so the loaded memos are validated before they're
+    /// used, in the same way as after setting an input. Memos for queries which are no longer persisted, or whose
+    /// key or value types or their `Persist::VERSION`s have changed, are skipped. Dip can't tell whether a query
+    /// function has changed, so files saved by a program whose query functions behave differently shouldn't be
+    /// loaded.
+    pub fn load(&mut self, path: impl AsRef<Path>) -> Result<(), LoadError> {
+        let bytes = fs::read(path)?;
+        let mut input = &bytes[..];
+        let magic = persist::take(&mut input, SAVE_FILE_MAGIC.len());
+        if magic != Some(SAVE_FILE_MAGIC) || u32::read(&mut input) != Some(SAVE_FILE_VERSION) {
+            return Err(LoadError::UnsupportedFormat);
+        }
+        let revision = usize::read(&mut input).ok_or(LoadError::Malformed)?;
+        let last_changed = Vec::<usize>::read(&mut input)
+            .and_then(|last_changed| last_changed.try_into().ok())
+            .ok_or(LoadError::Malformed)?;
+        let memos = self
+            .read_saved_memos(&mut input)
+            .ok_or(LoadError::Malformed)?;
+
+        // As when setting an input, memos which might depend on low durability inputs need checking, and volatile
+        // queries need rerunning.
+        self.wait_for_snapshots();
+        self.revision = revision + 1;
+        self.last_changed = last_changed;
+        self.last_changed[Durability::Low.index()] = self.revision;
+
+        let count = memos.len();
+        let evicted: Vec<Slot> = {
+            let mut table = self.shared.memos();
+            *table = MemoTable::new(&self.shared.registry);
+            memos
+                .into_iter()
+                .flat_map(|(slot, memo)| table.insert(slot, memo))
+                .collect()
+        };
+        event!(self, Event::Load, count, self.revision);
+        self.log_evictions(evicted);
+        Ok(())
+    }
+
+    /// Reads the rest of a file written by `save`, after the revisions. Returns the memos which can be loaded
+    /// into this database, or returns `None` if the file is malformed.
+    fn read_saved_memos(&self, input: &mut &[u8]) -> Option<Vec<(Slot, Memo)>> {
+        // Work out which of the saved queries are persisted in this database with the same types.
+        let mut loadable = HashMap::new();
+        for _ in 0..usize::read(input)? {
+            let (id, (key_type, value_type)) = <(String, ((String, u32), (String, u32)))>::read(input)?;
+            let (id, codec) = match self.shared.registry.queries.get_key_value(id.as_str()) {
+                Some((id, info)) => (*id, info.codec.as_ref()),
+                None => continue,
+            };
+            let same_type = |(name, version): (&str, u32), saved: &(String, u32)| name == saved.0 && version == saved.1;
+            let codec = codec.filter(|c| same_type(c.key_type, &key_type) && same_type(c.value_type, &value_type));
+            if let Some(codec) = codec {
+                loadable.insert(id, codec);
+            }
+        }
+
+        // Reads a slot, which is `None` if its query can't be loaded.
+        let read_slot = |input: &mut &[u8]| -> Option<Option<Slot>> {
+            let (id, key) = <(String, Vec<u8>)>::read(input)?;
+            let (id, codec) = match loadable.get_key_value(id.as_str()) {
+                Some((id, codec)) => (*id, codec),
+                None => return Some(None),
+            };
+            Some(Some(Slot::new(id, (codec.read_key)(&key)?)))
+        };
+
+        let mut memos = vec![];
+        for _ in 0..usize::read(input)? {
+            let slot = read_slot(input)?;
+            let value = Option::<Vec<u8>>::read(input)?;
+            let (verified_at, changed_at, durability) = <(usize, usize, Durability)>::read(input)?;
+            let mut dependencies = vec![];
+            let mut all_loadable = true;
+            for _ in 0..usize::read(input)? {
+                match read_slot(input)? {
+                    Some(dependency) => dependencies.push(dependency),
+                    None => all_loadable = false,
+                }
+            }
+
+            // A memo which is missing some of its dependencies can't be validated, so we skip it.
+            let slot = match slot.filter(|_| all_loadable) {
+                Some(slot) => slot,
+                None => continue,
+            };
+            let value = match value {
+                Some(bytes) => Some((loadable[slot.id].read_value)(&bytes)?),
+                None => None,
+            };
+            let memo = Memo {
+                value,
+                verified_at,
+                changed_at,
+                dependencies,
+                durability,
+                accumulated: vec![],
+            };
+            memos.push((slot, memo));
+        }
+
+        match input.is_empty() {
+            true => Some(memos),
+            false => None,
+        }
+    }
+
+    /// Returns an error if `slot` can't be set as an input to `Q`.
+    fn check_input<Q: Query>(&self, slot: &Slot) -> Result<(), DipError> {
+        if !self.shared.registry.is_input(slot.id) {
+            return Err(DipError::NotAnInput {
+                id: slot.id,
+                key: slot.key.clone(),
+            });
+        }
+        if !self.shared.registry.queries[slot.id].matches::<Q>() {
+            return Err(DipError::TypeMismatch {
+                id: slot.id,
+                key: slot.key.clone(),
+                path: vec![],
+            });
+        }
+        Ok(())
+    }
+
+    /// Stores a new value for an input query at the current revision.
+    fn write_input(&mut self, slot: Slot, value: Value, durability: Durability) {
+        event!(self, Event::Set, slot, value, self.revision);
+
+        // If a memo exists and the new value is the same as the old value then don't
+        // update `changed_at`.
+        let old_memo = self.read_memo(&slot);
+        let changed_at = old_memo
+            .as_ref()
+            .filter(|m| m.value.as_ref() == Some(&value))
+            .map(|m| m.changed_at)
+            .unwrap_or(self.revision);
+
+        // If this input has changed then so might any memo with durability no greater than this input's durability.
+        // If the durability of the input has been lowered then memos computed using the previous durability might
+        // be affected too.
+        //
+        // Volatile queries can change whenever the revision does. They have low durability, so low durability memos
+        // always need checking after an input is set.
+        self.last_changed[Durability::Low.index()] = self.revision;
+        let old_durability = old_memo.as_ref().map(|m| m.durability);
+        if changed_at == self.revision || old_durability != Some(durability) {
+            let max_durability = old_durability.map_or(durability, |d| d.max(durability));
+            for last_changed in &mut self.last_changed[..=max_durability.index()] {
+                *last_changed = self.revision;
+            }
+        }
+
+        // Input queries do not depend on any other queries, so their dependency sets are
+        // always empty.
+        let memo = Memo {
+            value: Some(value),
+            verified_at: self.revision,
+            changed_at,
+            dependencies: vec![],
+            durability,
+            accumulated: vec![],
+        };
+
+        // Helper method that stores the memo in `self.shared.memos` and emits an Event reporting this.
+        self.store_memo(slot, memo);
+    }
+
+    /// Removes the value of an input query at the current revision, if it has one.
+    fn remove_input(&mut self, slot: Slot) {
+        let old_memo = match self.shared.memos().remove(&slot) {
+            Some(memo) => memo,
+            None => return,
+        };
+        event!(self, Event::Remove, slot, self.revision);
+
+        // There's no memo left to record when this input changed, so `check_next_dependency` treats missing memos
+        // as having changed at the current revision. As with `write_input`, any memo with durability no greater
+        // than the removed value's might be affected.
+        for last_changed in &mut self.last_changed[..=old_memo.durability.index()] {
+            *last_changed = self.revision;
+        }
+    }
+
+    /// Computes or looks up the value for a query. This method is used for both input and derived queries.
+    ///
+    /// Panics if the query can't be evaluated. See `try_get` for a non-panicking version of this method.
+    pub fn get<Q: Query>(&mut self, key: Q::Key) -> Q::Value {
+        self.try_get::<Q>(key)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Computes or looks up the value for a query, or returns an error if this or any of the queries it reads
+    /// can't be evaluated.
+    pub fn try_get<Q: Query>(&mut self, key: Q::Key) -> Result<Q::Value, DipError> {
+        let slot = Slot::new(Q::ID, Key::new(key));
+
+        // Check that this query is registered with the same types as `Q` before we store or read any values for it.
+        self.check_query::<Q>(&slot)?;
+
+        let value = self.get_with_timestamp(slot, true)?.value.unwrap();
+        Ok(value.downcast::<Q::Value>().expect("Value type mismatch"))
+    }
+
+    /// Returns an error if `slot` isn't registered as a query with the same key and value types as `Q`.
+    fn check_query<Q: Query>(&self, slot: &Slot) -> Result<(), DipError> {
+        match self.shared.registry.queries.get(slot.id) {
+            Some(info) if info.matches::<Q>() => Ok(()),
+            Some(_) => Err(DipError::TypeMismatch {
+                id: slot.id,
+                key: slot.key.clone(),
+                path: self.active_path(),
+            }),
+            None => Err(DipError::UnknownQuery {
+                id: slot.id,
+                key: slot.key.clone(),
+                path: self.active_path(),
+            }),
+        }
+    }
+
+    /// Returns an error if `slot` is a transparent query, for methods which need its memo.
+    fn check_not_transparent(&self, slot: &Slot) -> Result<(), DipError> {
+        match self.shared.registry.derived(slot.id).is_some_and(|d| d.transparent) {
+            true => Err(DipError::Transparent {
+                id: slot.id,
+                key: slot.key.clone(),
+            }),
+            false => Ok(()),
+        }
+    }
+
+    /// Returns the items reported using the accumulator `A` while evaluating the query `Q` with key `key`, and
+    /// while evaluating all of the queries it (indirectly) depends on. Each item is returned along with the
+    /// query that reported it. Items reported by transparent queries are attributed to the queries reading them.
+    ///
+    /// The query and its dependencies are brought up to date first, so the items are those that would be
+    /// reported if every query was rerun from scratch.
+    ///
+    /// Panics if any of the queries can't be evaluated, or if `Q` is transparent. See `try_accumulated` for a
+    /// non-panicking version of this method.
+    pub fn accumulated<Q: Query, A: Accumulator>(&mut self, key: Q::Key) -> Vec<(QueryId, Key, A::Item)> {
+        self.try_accumulated::<Q, A>(key)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Returns the items reported using the accumulator `A` while evaluating the query `Q` with key `key` and its
+    /// dependencies, or returns an error if any of these queries can't be evaluated or `Q` is transparent. See
+    /// `accumulated`.
+    pub fn try_accumulated<Q: Query, A: Accumulator>(
+        &mut self,
+        key: Q::Key,
+    ) -> Result<Vec<(QueryId, Key, A::Item)>, DipError> {
+        let slot = Slot::new(Q::ID, Key::new(key));
+        self.check_query::<Q>(&slot)?;
+        // The items and dependencies of a transparent query are only recorded by the query reading it.
+        self.check_not_transparent(&slot)?;
+
+        // Visit each query in the order they were read, starting from `slot`.
+        let mut items = vec![];
+        let mut visited = HashSet::new();
+        let mut stack = vec![slot];
+        while let Some(slot) = stack.pop() {
+            if !visited.insert(slot.clone()) {
+                continue;
+            }
+            // Validating `slot` usually just checks that it was verified at this revision, but might need
+            // to recompute a memo which has been evicted, or one whose dependencies haven't been checked as the
+            // memo that read it was validated by durability.
+            self.get_with_timestamp(slot.clone(), false)?;
+            let memo = match self.with_memo(&slot, Memo::clone) {
+                Some(memo) => memo,
+                None => continue,
+            };
+            for (accumulator, item) in &memo.accumulated {
+                if *accumulator == TypeId::of::<A>() {
+                    let item = item.downcast::<A::Item>().expect("Item type mismatch");
+                    items.push((slot.id, slot.key.clone(), item));
+                }
+            }
+            stack.extend(memo.dependencies.iter().rev().cloned());
+        }
+        Ok(items)
+    }
+
+    /// Registers a callback to be run by `flush` whenever the value of the query `Q` with key `key` has changed.
+    ///
+    /// Panics if `Q` is not a registered query, or is transparent. See `try_subscribe` for a non-panicking version
+    /// of this method.
+    pub fn subscribe<Q: Query>(
+        &mut self,
+        key: Q::Key,
+        callback: impl FnMut(&Q::Value) + Send + Sync + 'static,
+    ) -> SubscriptionId {
+        self.try_subscribe::<Q>(key, callback)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Registers a callback to be run by `flush` whenever the value of the query `Q` with key `key` has changed,
+    /// or returns an error if `Q` is not a registered query, or is transparent.
+    pub fn try_subscribe<Q: Query>(
+        &mut self,
+        key: Q::Key,
+        mut callback: impl FnMut(&Q::Value) + Send + Sync + 'static,
+    ) -> Result<SubscriptionId, DipError> {
+        let slot = Slot::new(Q::ID, Key::new(key));
+        self.check_query::<Q>(&slot)?;
+        // Transparent queries always appear to have changed at the current revision, so would notify the
+        // subscriber after every change to any input.
+        self.check_not_transparent(&slot)?;
+
+        let id = SubscriptionId(self.next_subscription_id);
+        self.next_subscription_id += 1;
+        let callback: SubscriptionCallback = Box::new(move |value| {
+            callback(&value.downcast::<Q::Value>().expect("Value type mismatch"))
+        });
+        // Changes made before subscribing aren't reported.
+        self.subscriptions.push(Subscription {
+            id,
+            slot,
+            seen_at: self.revision,
+            callback,
+        });
+        Ok(id)
+    }
+
+    /// Removes a subscription registered using `subscribe`.
+    pub fn unsubscribe(&mut self, id: SubscriptionId) {
+        self.subscriptions.retain(|s| s.id != id);
+    }
+
+    /// Brings the value of every subscribed query up to date, and runs the callbacks for those whose values have
+    /// changed since the last flush.
+    ///
+    /// Only the subscribed queries and their dependencies are checked. Rerunning a query which produces the same
+    /// value as before doesn't update its `changed_at` revision, so doesn't run its callbacks.
+    ///
+    /// Panics if a subscribed query can't be evaluated. See `try_flush` for a non-panicking version of this method.
+    pub fn flush(&mut self) {
+        self.try_flush()
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Brings the value of every subscribed query up to date, and runs the callbacks for those whose values have
+    /// changed since the last flush, or returns the first error encountered while evaluating a subscribed query.
+    ///
+    /// Subscriptions which weren't checked before the error will report their changes at the next flush.
+    pub fn try_flush(&mut self) -> Result<(), DipError> {
+        let mut subscriptions = std::mem::take(&mut self.subscriptions);
+        let mut result = Ok(());
+        for subscription in &mut subscriptions {
+            let stamped = match self.get_with_timestamp(subscription.slot.clone(), true) {
+                Ok(stamped) => stamped,
+                Err(error) => {
+                    result = Err(error);
+                    break;
+                }
+            };
+            if stamped.changed_at > subscription.seen_at {
+                event!(self, Event::NotifySubscriber, subscription.slot, stamped.changed_at);
+                (subscription.callback)(&stamped.value.unwrap());
+            }
+            subscription.seen_at = self.revision;
+        }
+        self.subscriptions = subscriptions;
+        result
+    }
+
+    /// Computes or looks up the value for a query and returns the value along with the database revision
+    /// at which this value last changed. This is used for reads made from outside of any query function.
+    ///
+    /// If `need_value` is false then the caller only cares about when the value last changed, and the returned
+    /// value may be `None` if the memo's value has been discarded.
+    ///
+    /// Checking whether a memo is still valid involves checking whether each of its dependencies has changed,
+    /// which in turn may involve checking the dependencies of their memos, and so on. Rather than recursing once
+    /// for each level of the dependency graph, we keep a frame for each query being checked on `active_queries`
+    /// and drive the checks from the loop in `drive`. Each `Step` says what to do next.
+    ///
+    /// Query functions can't be paused in the same way, so when a query function reads another query we do
+    /// recurse. To avoid overflowing the stack on a long chain of queries, once `MAX_NESTED_QUERY_FUNCTIONS` are
+    /// running we don't start another. Instead we abandon every active query, read the query which needed
+    /// running from here, and then retry the original read. When the retry reaches the deferred query it uses the
+    /// value we read, so it gets further than before. This reruns the abandoned query functions, so evaluating a
+    /// long chain of queries for the first time runs most of them twice.
+    fn get_with_timestamp(&mut self, slot: Slot, need_value: bool) -> Result<StampedValue, DipError> {
+        // The reads we've deferred and not yet finished, most recent last, each with the path of queries which
+        // led to it from the read before.
+        let mut deferred: Vec<(Slot, Vec<(QueryId, Key)>)> = vec![];
+        let result = loop {
+            let result = match deferred.last() {
+                Some((slot, _)) => self.drive(slot.clone(), true),
+                None => self.drive(slot.clone(), need_value),
+            };
+            match result {
+                Ok(stamped) => match deferred.pop() {
+                    Some((slot, _)) => {
+                        self.deferred_values.insert(slot, stamped);
+                    }
+                    None => break Ok(stamped),
+                },
+                Err(ReadError::Failed(error)) => {
+                    let path: Vec<_> = deferred.into_iter().flat_map(|(_, path)| path).collect();
+                    break Err(error.prefix_path(&path));
+                }
+                // If the deferred query was abandoned by an earlier deferral then it's (indirectly) reading itself.
+                Err(ReadError::Deferred(slot, path)) => {
+                    let mut full_path: Vec<_> = deferred.iter().flat_map(|(_, path)| path.clone()).collect();
+                    full_path.extend(path.iter().cloned());
+                    if let Some(start) = full_path.iter().position(|(id, key)| *id == slot.id && *key == slot.key) {
+                        break Err(DipError::Cycle {
+                            cycle: full_path.split_off(start),
+                        });
+                    }
+                    deferred.push((slot, path));
+                }
+            }
+        };
+        self.deferred_values.clear();
+        result
+    }
+
+    /// Reads `slot`, as a dependency of the query at the top of `active_queries` if there is one, by running
+    /// `Step`s until the read has finished.
+    fn drive(&mut self, slot: Slot, need_value: bool) -> Result<StampedValue, ReadError> {
+        let base = self.active_queries.len();
+        let mut step = self.start_read(slot, need_value);
+        loop {
+            step = match step {
+                Step::Read(slot) => self.start_read(slot, false),
+                Step::CheckDependencies => self.check_next_dependency(),
+                Step::Recompute(memo, memo_is_valid) => self.recompute(memo, memo_is_valid),
+                // If there's an active query above `base` then we're checking its dependencies, and have just
+                // finished reading one of them.
+                Step::Finished(result) if self.active_queries.len() > base => self.dependency_read(result),
+                Step::Finished(result) => return result,
+            };
+        }
+    }
+
+    /// Starts reading `slot`, as a dependency of the query at the top of `active_queries` if there is one.
+    fn start_read(&mut self, slot: Slot, need_value: bool) -> Step {
+        // If a write is waiting for this snapshot to be dropped then there's no point continuing, as anything we
+        // compute will be out of date. `QueryContext::try_get` unwinds on this error rather than returning it,
+        // so it unwinds through every active query and no memo is stored for a query which didn't finish.
+        if self.shared.cancelled.load(Ordering::SeqCst) {
+            event!(self, Event::Cancelled);
+            return Step::Finished(Err(DipError::Cancelled.into()));
+        }
+        if self.worker_cancelled.as_ref().is_some_and(|c| c.load(Ordering::SeqCst)) {
+            event!(self, Event::WorkerCancelled);
+            return Step::Finished(Err(DipError::Cancelled.into()));
+        }
+
+        event!(self, Event::Get, slot);
+
+        // If we called into this method as part of computing or validating the output for another query
+        // then record this call as a dependency of the parent query.
+        //
+        // When we store a `Memo` with the output of a query we read its dependencies from `active_queries`
+        // and store them in the memo.
+        //
+        // Transparent queries have no memo to check, so the parent query depends on whatever they read instead.
+        // Once too many query functions are running we read them like any other derived query instead, so that
+        // running them can be deferred. No memo is stored for them, so the parent query is rerun whenever it's
+        // validated at a new revision, and any items they accumulate are lost.
+        let transparent = self.shared.registry.derived(slot.id).is_some_and(|d| d.transparent)
+            && !self.must_defer_query_functions();
+        if let Some(active) = self.active_queries.last_mut().filter(|_| !transparent) {
+            active.add_dependency(slot.clone());
+        }
+
+        // If this query is already active then it has (indirectly) called itself, and evaluating it again would
+        // loop forever. The cycle consists of every query from the earlier activation upwards.
+        //
+        // Cycle-tolerant queries instead return their current provisional value. See `execute` for how these
+        // values are refined.
+        if let Some(&start) = self.active_indexes.get(&slot) {
+            let active = &mut self.active_queries[start];
+            if let Some(provisional_value) = active.provisional_value.clone() {
+                active.provisional_value_read = true;
+                event!(self, Event::ReadProvisionalValue, provisional_value);
+                let stamped = StampedValue::new(Some(provisional_value), self.revision, Durability::Low);
+                return Step::Finished(Ok(stamped));
+            }
+            let cycle = self.active_path().split_off(start);
+            return Step::Finished(Err(DipError::Cycle { cycle }.into()));
+        }
+
+        if transparent {
+            return Step::Finished(self.run_transparent_query(slot));
+        }
+
+        // Make this the currently active query.
+        let provisional_value = self
+            .shared
+            .registry
+            .derived(slot.id)
+            .and_then(|derived| derived.cycle_initial.clone());
+        self.push_active_query(slot.clone(), provisional_value);
+        self.active_queries.last_mut().unwrap().need_value = need_value;
+
+        self.read(&slot)
+    }
+
+    /// Runs the query function for a transparent query, and passes the dependencies it reads on to the
+    /// parent query.
+    ///
+    /// We still push an entry onto `active_queries` so that cycles through transparent queries are detected,
+    /// and so that they appear in the paths reported in errors.
+    fn run_transparent_query(&mut self, slot: Slot) -> Result<StampedValue, ReadError> {
+        event!(self, Event::TransparentQuery);
+        self.push_active_query(slot.clone(), None);
+        let result = self.run_query_function(&slot);
+        let active = self.pop_active_query().unwrap();
+
+        if let Some(parent) = self.active_queries.last_mut() {
+            for dependency in active.dependencies {
+                parent.add_dependency(dependency);
+            }
+            parent.durability = parent.durability.min(active.durability);
+            parent.accumulated.extend(active.accumulated);
+        }
+
+        // Transparent queries are never recorded as dependencies, so nothing uses `changed_at`.
+        let value = result?;
+        Ok(StampedValue::new(Some(value), self.revision, active.durability))
+    }
+
+    /// Removes the top element of `active_queries` now that we're done with it, and returns the result of
+    /// reading its slot.
+    fn finish_read(&mut self, result: Result<StampedValue, ReadError>) -> Step {
+        let active = self.pop_active_query().unwrap();
+
+        // A query is only as durable as the least durable query it reads.
+        if let (Ok(stamped), Some(parent)) = (&result, self.active_queries.last_mut()) {
+            parent.durability = parent.durability.min(stamped.durability);
+        }
+
+        // If this was a cycle-tolerant query then any memos stored while it was active may have been computed
+        // from a provisional value of an enclosing cycle-tolerant query, or from a provisional value of this
+        // query if evaluation failed part way through a fixpoint iteration.
+        if result.is_ok() {
+            self.record_overwritten_memos(active.overwritten_memos);
+        } else {
+            self.restore_overwritten_memos(active.overwritten_memos);
+        }
+
+        // Once the outermost cycle-tolerant query has finished, the memos stored while it was active either
+        // have their final values or have been restored to their previous contents, so can be shared.
+        if !self.provisional_memos.is_empty() && !self.cycle_tolerant_query_is_active() {
+            let memos = std::mem::take(&mut self.provisional_memos);
+            let evicted: Vec<Slot> = {
+                let mut table = self.shared.memos();
+                memos
+                    .into_iter()
+                    .flat_map(|(slot, memo)| table.insert(slot, memo))
+                    .collect()
+            };
+            self.log_evictions(evicted);
+        }
+
+        // Any thread waiting for this query to finish can now read its memo, or run it again if it failed.
+        if active.claimed {
+            self.release(&active.slot);
+        }
+
+        Step::Finished(result)
+    }
+
+    /// The body of `start_read` after recording this query as a dependency of the parent query (if any)
+    /// and pushing a new entry onto the active query stack.
+    fn read(&mut self, slot: &Slot) -> Step {
+        // Helper method that looks up the memo in this slot and emits an Event reporting this.
+        let memo = self.read_memo(slot);
+
+        if self.shared.registry.is_input(slot.id) {
+            // If this is an input query then we require the user to have provided a value via `.set(..)`.
+            let memo = match memo {
+                Some(memo) => memo,
+                None => {
+                    let (id, key, path) = self.split_active_path();
+                    return self.finish_read(Err(DipError::InputNotSet { id, key, path }.into()));
+                }
+            };
+
+            event!(self, Event::MemoForInputQuery);
+
+            // If this is the first read of this input at the current revision then update the memo to reflect this.
+            // Note that memoised values for inputs are always valid - they can't be invalidated by changes to the
+            // values of any other queries.
+            //
+            // Aside, short version:
+            //      We could have chosen to handle input queries in any of several other basically equivalent ways.
+            //
+            // Aside, longer version:
+            //      If you're wondering why we care about `verified_at` for inputs when we've just stated that input
+            //      `Memo`s are always valid, the answer is that it doesn't really matter either way.
+            //
+            //      The only significance of updating `verified_at` here is that it lets `check_next_dependency`
+            //      below use the memo directly, rather than reading the input again. This has no effect on the
+            //      the set of query functions that get run, but saves a bit of pushing to and popping from the active
+            //      query stack. We could also have chosen to special case inputs inside `check_next_dependency`, or
+            //      to update `verified_at` for all inputs whenever a new value is set for _any_ input query, or made
+            //      the field optional and omitted it for inputs, or chosen from a range of yet other possibilities,
+            //      without changing the algorithm or calculations performed in any material way.
+            //
+            //      We could also have noted that checking the (empty) dependencies of input memos would always find
+            //      that none had changed, and that special casing inputs is not strictly necessary. But handling
+            //      inputs separately seemed slightly clearer.
+            //
+            if memo.verified_at != self.revision {
+                let new_memo = Memo {
+                    verified_at: self.revision,
+                    ..memo.clone()
+                };
+                self.store_memo(slot.clone(), new_memo);
+            }
+
+            return self.finish_read(Ok(StampedValue::new(memo.value, memo.changed_at, memo.durability)));
+        }
+
+        // If we have a memo and this isn't an input query then we need to check if the memoized value is still valid.
+        let memo = match memo {
+            Some(memo) => memo,
+            None => return Step::Recompute(None, false),
+        };
+
+        // If we've verified the memo already at this revision then it must be usable.
+        if memo.verified_at == self.revision {
+            event!(self, Event::MemoVerifiedAtCurrentRevision);
+            let durability = memo.durability;
+            return self.memo_is_valid(memo, durability);
+        }
+
+        // Volatile queries read state from outside of the database, so their memos can't be trusted beyond the
+        // revision at which they were computed. We skip the checks below and rerun the query function.
+        if self.shared.registry.derived(slot.id).unwrap().volatile {
+            event!(self, Event::MemoForVolatileQuery);
+            return Step::Recompute(Some(memo), false);
+        }
+
+        // If no inputs with at least the durability of this memo have changed since it was verified then
+        // none of its dependencies can have changed either, so we don't need to check them individually.
+        let last_changed = self.last_changed[memo.durability.index()];
+        if memo.verified_at >= last_changed {
+            event!(self, Event::MemoValidByDurability, memo.durability, last_changed);
+            let memo = Memo {
+                verified_at: self.revision,
+                ..memo
+            };
+            self.store_memo(slot.clone(), memo.clone());
+            let durability = memo.durability;
+            return self.memo_is_valid(memo, durability);
+        }
+
+        // Otherwise, we need to check the dependencies of the memo to see if any of their values have changed
+        // since the memo was last verified. See `check_next_dependency`.
+        event!(self, Event::StartedInputChecks, memo.verified_at);
+        self.active_queries.last_mut().unwrap().validating = Some((memo, 0));
+        Step::CheckDependencies
+    }
+
+    /// Checks the dependencies of the memo being validated by the query at the top of `active_queries`, in order,
+    /// until we find one which has changed since the memo was verified or need to read one to find out.
+    ///
+    /// If we have an up to date memo for a dependency then we can use its `changed_at` field directly. Otherwise,
+    /// we return `Step::Read` so that `get_with_timestamp` reads the dependency, and then continue from
+    /// `dependency_read` once it has finished.
+    fn check_next_dependency(&mut self) -> Step {
+        loop {
+            let (memo, next) = self.active_queries.last().unwrap().validating.as_ref().unwrap();
+            let dependency = match memo.dependencies.get(*next) {
+                Some(dependency) => dependency.clone(),
+                None => break,
+            };
+
+            let stamps = self.with_memo(&dependency, |m| (m.verified_at, m.changed_at, m.durability));
+            let changed_at = match stamps {
+                // If there's no memo then either an input value has been removed or a derived memo has been
+                // evicted. Recomputing an evicted memo would give it a `changed_at` of the current revision, as
+                // there'd be no old value to compare against, so either way we treat the dependency as changed.
+                // We don't read the query again here - if the query we're validating still depends on it then it
+                // will be recomputed when the query is rerun.
+                None => self.revision,
+                // If we've verified the memo this revision then we can trust its changed_at field. Reading the
+                // dependency would have recorded its durability, so we need to as well.
+                Some((verified_at, changed_at, durability)) if verified_at == self.revision => {
+                    let active = self.active_queries.last_mut().unwrap();
+                    active.durability = active.durability.min(durability);
+                    changed_at
+                }
+                // If we've not verified the memo this revision then we need to read it. For queries with many
+                // dependencies we might read all of the remaining ones at once.
+                Some(_) if self.can_validate_in_parallel() => {
+                    if self.dependencies_changed_on_worker_threads() {
+                        event!(self, Event::CompletedInputChecks, true);
+                        let (memo, _) = self.active_queries.last_mut().unwrap().validating.take().unwrap();
+                        return Step::Recompute(Some(memo), false);
+                    }
+                    let (memo, next) = self.active_queries.last_mut().unwrap().validating.as_mut().unwrap();
+                    *next = memo.dependencies.len();
+                    continue;
+                }
+                Some(_) => return Step::Read(dependency),
+            };
+            if let Some(step) = self.dependency_checked(dependency, changed_at) {
+                return step;
+            }
+        }
+
+        // If the values used when computing this memo have not changed then the memo is still valid and we can
+        // update the memo's `verified_at` field. The durabilities of the dependencies may have changed, so we also
+        // update the memo's durability.
+        //
+        // That's unless this is a cycle-tolerant query which was re-entered while checking its dependencies. The
+        // checks used its initial provisional value, so their results can't be trusted. Rerunning it to a fixpoint
+        // restores any memos computed from that value before recomputing them.
+        let active = self.active_queries.last_mut().unwrap();
+        if active.provisional_value_read {
+            event!(self, Event::CompletedInputChecks, true);
+            let (memo, _) = active.validating.take().unwrap();
+            return Step::Recompute(Some(memo), false);
+        }
+        event!(self, Event::CompletedInputChecks, false);
+        let active = self.active_queries.last_mut().unwrap();
+        let (memo, _) = active.validating.take().unwrap();
+        let memo = Memo {
+            verified_at: self.revision,
+            durability: active.durability,
+            ..memo
+        };
+        let slot = active.slot.clone();
+        self.store_memo(slot, memo.clone());
+        let durability = memo.durability;
+        self.memo_is_valid(memo, durability)
+    }
+
+    /// Whether the remaining dependencies of the memo being validated by the query at the top of `active_queries`
+    /// can be read by worker threads. See `DatabaseBuilder::parallel_validation`.
+    fn can_validate_in_parallel(&self) -> bool {
+        let active = self.active_queries.last().unwrap();
+        let (memo, next) = active.validating.as_ref().unwrap();
+        let derived = self.shared.registry.derived(active.slot.id).unwrap();
+        // Workers can't see provisional values or memos, and would wait forever for any query that we've claimed,
+        // as we're not going to finish running it until they've finished.
+        derived.parallel_validation
+            && self.worker_cancelled.is_none()
+            && memo.dependencies.len() - next > 1
+            && self.shared.validation_workers > 1
+            && !self
+                .active_queries
+                .iter()
+                .any(|a| a.claimed || a.provisional_value.is_some())
+    }
+
+    /// Reads the remaining dependencies of the memo being validated by the query at the top of `active_queries`
+    /// on worker threads, and returns whether any of them has changed since the memo was verified.
+    ///
+    /// The workers are scoped threads started by this call, and each reads dependencies using its own handle on
+    /// the shared memos, in the same way as a `Snapshot`. Each worker is given a contiguous block of the
+    /// dependencies and reads them in order. When a worker finds a dependency which has changed, it cancels the
+    /// workers for later blocks, so that as with sequential validation we avoid reading dependencies after the
+    /// first changed one, which the query might no longer read when it's rerun. Workers for earlier blocks carry
+    /// on, as they might still find an earlier change.
+    ///
+    /// A dependency which can't be evaluated, or whose query function panics, is treated as having changed. The
+    /// query is rerun, and reports the error or panics itself if it still reads that dependency. Otherwise a
+    /// worker could fail reading a dependency that the query no longer reads, as sequential validation would
+    /// have stopped at an earlier change.
+    fn dependencies_changed_on_worker_threads(&mut self) -> bool {
+        let active = self.active_queries.last().unwrap();
+        let (memo, next) = active.validating.as_ref().unwrap();
+        let (dependencies, verified_at) = (&memo.dependencies[*next..], memo.verified_at);
+        let workers = self.shared.validation_workers.min(dependencies.len());
+        let blocks: Vec<_> = dependencies.chunks(dependencies.len().div_ceil(workers)).collect();
+        event!(self, Event::StartedParallelInputChecks, dependencies.len(), blocks.len());
+
+        let cancelled: Vec<_> = blocks.iter().map(|_| Arc::new(AtomicBool::new(false))).collect();
+        let changed = AtomicBool::new(false);
+        let results: Vec<_> = thread::scope(|scope| {
+            let workers: Vec<_> = blocks
+                .iter()
+                .enumerate()
+                .map(|(index, block)| {
+                    let mut db = Database::from_shared(self.shared.clone(), self.revision, self.last_changed);
+                    db.worker_cancelled = Some(cancelled[index].clone());
+                    let (later_blocks, changed) = (&cancelled[index + 1..], &changed);
+                    scope.spawn(move || {
+                        let mut durability = Durability::High;
+                        for dependency in block.iter() {
+                            let read = || db.get_with_timestamp(dependency.clone(), false);
+                            match panic::catch_unwind(AssertUnwindSafe(read)) {
+                                Ok(Ok(stamped)) if stamped.changed_at <= verified_at => {
+                                    durability = durability.min(stamped.durability)
+                                }
+                                // An earlier block has a changed dependency, so the rest of this one doesn't matter.
+                                _ if db.worker_cancelled.as_ref().unwrap().load(Ordering::SeqCst) => break,
+                                _ => {
+                                    changed.store(true, Ordering::SeqCst);
+                                    for later_block in later_blocks {
+                                        later_block.store(true, Ordering::SeqCst);
+                                    }
+                                    break;
+                                }
+                            }
+                        }
+                        durability
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|worker| worker.join()).collect()
+        });
+
+        // As in `check_next_dependency`, the memo's durability includes that of every dependency we've checked.
+        let mut durability = Durability::High;
+        for result in results {
+            match result {
+                Ok(worker_durability) => durability = durability.min(worker_durability),
+                // As in `query_function_unwound`, nothing will catch this before it reaches the user, so every
+                // active query has been abandoned.
+                Err(payload) => {
+                    self.abandon_active_queries();
+                    panic::resume_unwind(payload)
+                }
+            }
+        }
+        let active = self.active_queries.last_mut().unwrap();
+        active.durability = active.durability.min(durability);
+        changed.into_inner()
+    }
+
+    /// Continues validating the memo at the top of `active_queries` after reading its next dependency.
+    fn dependency_read(&mut self, result: Result<StampedValue, ReadError>) -> Step {
+        let changed_at = match result {
+            Ok(stamped) => stamped.changed_at,
+            Err(error) => return self.finish_read(Err(error)),
+        };
+        let (memo, next) = self.active_queries.last().unwrap().validating.as_ref().unwrap();
+        let dependency = memo.dependencies[*next].clone();
+        self.dependency_checked(dependency, changed_at)
+            .unwrap_or(Step::CheckDependencies)
+    }
+
+    /// Records that `dependency` of the memo being validated last changed at `changed_at`. If this is after the
+    /// memo was verified then the memo is out of date, so we return the step which recomputes it.
+    fn dependency_checked(&mut self, dependency: Slot, changed_at: usize) -> Option<Step> {
+        event!(self, Event::ChangedAt, dependency, changed_at);
+        let active = self.active_queries.last_mut().unwrap();
+        let (memo, next) = active.validating.as_mut().unwrap();
+        if changed_at <= memo.verified_at {
+            *next += 1;
+            return None;
+        }
+        event!(self, Event::CompletedInputChecks, true);
+        let (memo, _) = self.active_queries.last_mut().unwrap().validating.take().unwrap();
+        Some(Step::Recompute(Some(memo), false))
+    }
+
+    /// Returns the value of a valid memo for the query at the top of `active_queries`.
+    fn memo_is_valid(&mut self, memo: Memo, durability: Durability) -> Step {
+        // Memos whose values have been discarded can still be valid. If the caller needs the value then
+        // we recompute it, but don't need to compare it with the previous value.
+        if memo.value.is_some() || !self.active_queries.last().unwrap().need_value {
+            return self.finish_read(Ok(StampedValue::new(memo.value, memo.changed_at, durability)));
+        }
+        event!(self, Event::MemoHasNoValue);
+        Step::Recompute(Some(memo), true)
+    }
+
+    /// Evaluates the query function for the query at the top of `active_queries`, and stores a new memo.
+    ///
+    /// If we got to this point then either we don't have a memoised value, it's out of date, or its value
+    /// has been discarded.
+    ///
+    /// This method is on the stack whenever a query function is running, so the work before and after running
+    /// the query function is done by other methods, to keep it small.
+    fn recompute(&mut self, memo: Option<Memo>, memo_is_valid: bool) -> Step {
+        let slot = self.active_queries.last().unwrap().slot.clone();
+
+        if self.must_defer_query_functions() {
+            return self.defer_read(slot);
+        }
+
+        // If another thread was already running this query then it has probably stored an up to date memo by
+        // now, so we start reading this query again. The other thread might also have finished between us
+        // reading the memo and claiming the query, in which case we release the query and do the same.
+        match self.claim(&slot) {
+            Ok(true) if !self.has_usable_memo(&slot) => {}
+            Ok(claimed) => {
+                let active = self.active_queries.last_mut().unwrap();
+                active.clear_recorded();
+                if claimed {
+                    active.claimed = false;
+                    self.release(&slot);
+                }
+                return self.read(&slot);
+            }
+            Err(error) => return self.finish_read(Err(error.into())),
+        }
+
+        match self.execute(&slot) {
+            Ok(new_value) => self.store_recomputed(memo, memo_is_valid, new_value),
+            Err(error) => self.finish_read(Err(error)),
+        }
+    }
+
+    /// Whether `slot`, which must be the top element of `active_queries`, has a memo which was verified at this
+    /// revision and has a value if one is needed.
+    fn has_usable_memo(&self, slot: &Slot) -> bool {
+        let need_value = self.active_queries.last().unwrap().need_value;
+        self.with_memo(slot, |m| m.verified_at == self.revision && (m.value.is_some() || !need_value))
+            .unwrap_or(false)
+    }
+
+    /// Called instead of running the query function for the query at the top of `active_queries` when too many
+    /// query functions are already running. See `get_with_timestamp`.
+    ///
+    /// If the read has already been deferred then we use the value read by `get_with_timestamp`. Otherwise we ask
+    /// `get_with_timestamp` to read this query and then retry the read which led here.
+    fn defer_read(&mut self, slot: Slot) -> Step {
+        if let Some(stamped) = self.deferred_values.get(&slot).cloned() {
+            event!(self, Event::UseDeferredValue);
+            return self.finish_read(Ok(stamped));
+        }
+        event!(self, Event::DeferRead, slot);
+        let (_, _, path) = self.split_active_path();
+        self.finish_read(Err(ReadError::Deferred(slot, path)))
+    }
+
+    /// Stores a new memo for the query at the top of `active_queries`, after running its query function.
+    fn store_recomputed(&mut self, memo: Option<Memo>, memo_is_valid: bool, new_value: Value) -> Step {
+        let slot = self.active_queries.last().unwrap().slot.clone();
+
+        // Some logging.
+        let old_value = memo.as_ref().and_then(|m| m.value.clone());
+        if let Some(old_value) = old_value.filter(|_| !memo_is_valid) {
+            event!(self, Event::ValueComparison, old_value, new_value, self.revision);
+        }
+
+        // If we had a memo before and the query's value hasn't actually changed then
+        // we don't update `changed_at`.
+        let changed_at = memo
+            .filter(|m| memo_is_valid || m.value.as_ref() == Some(&new_value))
+            .map(|m| m.changed_at)
+            .unwrap_or(self.revision);
+
+        // Store the new memo, recording its dependencies by reading from the top element of from `active_queries`.
+        // Volatile queries may change at any revision, so have low durability regardless of their dependencies.
+        // Dependency-only queries keep everything except the value.
+        let derived = self.shared.registry.derived(slot.id).unwrap();
+        let (volatile, dependencies_only) = (derived.volatile, derived.dependencies_only);
+        let active = self.active_queries.last().unwrap();
+        let durability = match volatile {
+            true => Durability::Low,
+            false => active.durability,
+        };
+        let memo = Memo {
+            value: Some(new_value.clone()).filter(|_| !dependencies_only),
+            verified_at: self.revision,
+            changed_at,
+            dependencies: active.dependencies.clone(),
+            durability,
+            accumulated: active.accumulated.clone(),
+        };
+
+        self.store_memo(slot, memo);
+        self.finish_read(Ok(StampedValue::new(Some(new_value), changed_at, durability)))
+    }
+
+    /// Evaluates the query function for `slot`, which must be the top element of `active_queries`.
+    ///
+    /// For most queries this just calls `run_query_function`. Cycle-tolerant queries are rerun until
+    /// they reach a fixpoint. See `execute_to_fixpoint`.
+    fn execute(&mut self, slot: &Slot) -> Result<Value, ReadError> {
+        // If we've just found that an existing memo is out of date then the dependencies we checked have been
+        // recorded against this query. These aren't necessarily the queries that the query function will read.
+        let active = self.active_queries.last_mut().unwrap();
+        active.clear_recorded();
+
+        match active.provisional_value.clone() {
+            Some(initial) => self.execute_to_fixpoint(slot, initial),
+            None => self.run_query_function(slot),
+        }
+    }
+
+    /// Evaluates the query function for the cycle-tolerant query `slot`, which must be the top element of
+    /// `active_queries`. Each run uses the output of the previous run as the provisional value returned when the
+    /// query is re-entered, and we stop once a run either doesn't re-enter the query or returns its provisional
+    /// value unchanged.
+    fn execute_to_fixpoint(&mut self, slot: &Slot, initial: Value) -> Result<Value, ReadError> {
+        let mut provisional_value = initial;
+        for iteration in 1..=MAX_FIXPOINT_ITERATIONS {
+            event!(self, Event::StartedFixpointIteration, iteration, provisional_value);
+
+            // Discard anything computed from the provisional value used in the previous iteration.
+            let active = self.active_queries.last_mut().unwrap();
+            let overwritten_memos = std::mem::take(&mut active.overwritten_memos);
+            active.provisional_value = Some(provisional_value.clone());
+            active.provisional_value_read = false;
+            active.clear_recorded();
+            self.restore_overwritten_memos(overwritten_memos);
+
+            let value = self.run_query_function(slot)?;
+
+            if !self.active_queries.last().unwrap().provisional_value_read
+                || value == provisional_value
+            {
+                return Ok(value);
+            }
+            provisional_value = value;
+        }
+
+        let (id, key, path) = self.split_active_path();
+        Err(DipError::FixpointDidNotConverge { id, key, path }.into())
+    }
+
+    /// Find the query function with id `slot.id` and run it.
+    /// Recall that query functions have signature `Fn(&mut QueryContext, Q::Key) -> Q::Value`.
+    /// See `one_year_fee_query` in examples/walkthrough.rs for an example.
+    ///
+    /// If a read made by the query function fails then `QueryContext::get` stashes the error and unwinds, and
+    /// we catch the unwind here and return the error.
+    fn run_query_function(&mut self, slot: &Slot) -> Result<Value, ReadError> {
+        let function = self
+            .shared
+            .registry
+            .derived(slot.id)
+            .expect("Missing query function")
+            .function
+            .clone();
+
+        event!(self, Event::StartedQueryEvaluation);
+        let depth = self.active_queries.len();
+        self.query_function_depth += 1;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            function(&mut QueryContext { db: self }, &slot.key)
+        }));
+        self.query_function_depth -= 1;
+        event!(self, Event::CompletedQueryEvaluation);
+
+        result.map_err(|payload| self.query_function_unwound(depth, payload))
+    }
+
+    /// Handles a query function which has unwound rather than returning a value, given the depth of the active
+    /// query stack when it started. Returns the reason stashed by `QueryContext::get`, or keeps unwinding if the
+    /// query function panicked for some other reason.
+    fn query_function_unwound(&mut self, depth: usize, payload: Box<dyn Any + Send>) -> ReadError {
+        // Any active queries pushed by the query function have been abandoned.
+        for active in self.active_queries.drain(depth..) {
+            self.active_indexes.remove(&active.slot);
+        }
+        match (payload.downcast::<QueryFailed>(), self.unwinding_error.take()) {
+            (Ok(_), Some(error)) => error,
+            // This wasn't one of our errors, so keep unwinding. Nothing will catch it before it reaches
+            // the user, so every active query has been abandoned.
+            (Ok(payload), None) => {
+                self.abandon_active_queries();
+                panic::resume_unwind(payload)
+            }
+            (Err(payload), _) => {
+                self.abandon_active_queries();
+                panic::resume_unwind(payload)
+            }
+        }
+    }
+
+    /// Removes every active query, releasing any queries we've claimed so that other threads don't wait for
+    /// them forever. Any memos which might have been computed from provisional values are discarded.
+    fn abandon_active_queries(&mut self) {
+        for active in std::mem::take(&mut self.active_queries) {
+            if active.claimed {
+                self.release(&active.slot);
+            }
+        }
+        self.active_indexes.clear();
+        self.provisional_memos.clear();
+    }
+
+    /// Claims `slot`, which must be the top element of `active_queries`, before running its query function, so
+    /// that other threads wait for us rather than running it too. Returns false if another thread had already
+    /// claimed it, in which case we first wait for that thread to finish.
+    ///
+    /// If the other thread is (indirectly) waiting for a query that we've claimed then neither thread could ever
+    /// finish, so we return a cycle error instead of waiting.
+    fn claim(&mut self, slot: &Slot) -> Result<bool, DipError> {
+        let mut in_progress = self.shared.in_progress.lock().unwrap();
+        let mut owner = match in_progress.owners.get(slot) {
+            Some(owner) => *owner,
+            None => {
+                in_progress.owners.insert(slot.clone(), self.handle);
+                self.active_queries.last_mut().unwrap().claimed = true;
+                return Ok(true);
+            }
+        };
+
+        // The part of an active query path from the query at `start` up to, but excluding, the query at the
+        // top of the path, which is the one being waited for.
+        fn segment(path: &[(QueryId, Key)], start: &Slot) -> Vec<(QueryId, Key)> {
+            let start = path
+                .iter()
+                .position(|(id, key)| *id == start.id && *key == start.key)
+                .unwrap();
+            path[start..path.len() - 1].to_vec()
+        }
+
+        // Follow the chain of threads waiting for each other. If it leads back to us then each thread on the
+        // chain contributes the part of its active query path from the query it claimed to the query it's
+        // waiting for.
+        let mut waited_for = slot.clone();
+        let mut segments = vec![];
+        while owner != self.handle {
+            let (next, path) = match in_progress.waiting.get(&owner) {
+                Some(waiting) => waiting,
+                // This thread isn't waiting for anyone, so will eventually finish.
+                None => break,
+            };
+            segments.push(segment(path, &waited_for));
+            waited_for = next.clone();
+            owner = in_progress.owners[next];
+        }
+        if owner == self.handle {
+            let mut cycle = segment(&self.active_path(), &waited_for);
+            cycle.extend(segments.into_iter().flatten());
+            return Err(DipError::Cycle { cycle });
+        }
+
+        // `release` removes our entry from `waiting` once the query has finished.
+        event!(self, Event::WaitForOtherThread, slot);
+        in_progress
+            .waiting
+            .insert(self.handle, (slot.clone(), self.active_path()));
+        while in_progress.waiting.contains_key(&self.handle) {
+            in_progress = self.shared.query_finished.wait(in_progress).unwrap();
+        }
+        Ok(false)
+    }
+
+    /// Releases a slot claimed using `claim`, and wakes up any threads waiting for it.
+    fn release(&self, slot: &Slot) {
+        let mut in_progress = self.shared.in_progress.lock().unwrap();
+        in_progress.owners.remove(slot);
+        in_progress.waiting.retain(|_, (waiting_for, _)| waiting_for != slot);
+        self.shared.query_finished.notify_all();
+    }
+
+    /// Returns the id and key of the query at the top of `active_queries`, along with the path by
+    /// which it was read. Used when building `DipError`s.
+    fn split_active_path(&self) -> (QueryId, Key, Vec<(QueryId, Key)>) {
+        let mut path = self.active_path();
+        let (id, key) = path.pop().unwrap();
+        (id, key, path)
+    }
+
+    /// The (id, key) pairs of all active queries, outermost first.
+    fn active_path(&self) -> Vec<(QueryId, Key)> {
+        self.active_queries
+            .iter()
+            .map(|a| (a.slot.id, a.slot.key.clone()))
+            .collect()
+    }
+
+    fn push_active_query(&mut self, slot: Slot, provisional_value: Option<Value>) {
+        event!(self, Event::PushActiveQuery);
+        let index = self.active_queries.len();
+        let innermost_cycle_tolerant = match provisional_value {
+            Some(_) => Some(index),
+            None => self.active_queries.last().and_then(|a| a.innermost_cycle_tolerant),
+        };
+        self.active_indexes.insert(slot.clone(), index);
+        self.active_queries
+            .push(ActiveQuery::new(slot, provisional_value, innermost_cycle_tolerant));
+    }
+
+    fn pop_active_query(&mut self) -> Option<ActiveQuery> {
+        event!(self, Event::PopActiveQuery);
+        let active = self.active_queries.pop()?;
+        self.active_indexes.remove(&active.slot);
+        Some(active)
+    }
+
+    fn store_memo(&mut self, slot: Slot, memo: Memo) {
+        let old_memo = self.with_memo(&slot, Memo::clone);
+        event!(self, Event::StoreMemo, old_memo, memo);
+        if self.record_overwritten_memos(std::iter::once((slot.clone(), old_memo))) {
+            self.provisional_memos.insert(slot, memo);
+        } else {
+            let evicted = self.shared.memos().insert(slot, memo);
+            self.log_evictions(evicted);
+        }
+    }
+
+    /// Records the previous contents of some slots in the innermost active cycle-tolerant query, if there is one,
+    /// and returns whether there is.
+    fn record_overwritten_memos(&mut self, memos: impl IntoIterator<Item = (Slot, Option<Memo>)>) -> bool {
+        let innermost = self.active_queries.last().and_then(|a| a.innermost_cycle_tolerant);
+        match innermost {
+            Some(index) => {
+                let active = &mut self.active_queries[index];
+                for (slot, memo) in memos {
+                    active.overwritten_memos.entry(slot).or_insert(memo);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether too many query functions are running to start another. See `get_with_timestamp`.
+    fn must_defer_query_functions(&self) -> bool {
+        // Abandoning a cycle-tolerant query would lose the progress made towards its fixpoint, so we don't defer
+        // reads while one is active.
+        self.query_function_depth >= MAX_NESTED_QUERY_FUNCTIONS && !self.cycle_tolerant_query_is_active()
+    }
+
+    fn cycle_tolerant_query_is_active(&self) -> bool {
+        self.active_queries.last().is_some_and(|a| a.innermost_cycle_tolerant.is_some())
+    }
+
+    /// Overwritten memos are only ever restored while they're held in `provisional_memos`, as they were all stored
+    /// while a cycle-tolerant query was active.
+    fn restore_overwritten_memos(&mut self, memos: HashMap<Slot, Option<Memo>>) {
+        for (slot, memo) in memos {
+            match memo {
+                Some(memo) => self.provisional_memos.insert(slot, memo),
+                None => self.provisional_memos.remove(&slot),
+            };
+        }
+    }
+
+    fn read_memo(&mut self, slot: &Slot) -> Option<Memo> {
+        let (memo, evicted) = match self.provisional_memos.get(slot) {
+            Some(memo) => (Some(memo.clone()), vec![]),
+            None => {
+                let mut memos = self.shared.memos();
+                let memo = memos.storage.get(slot).cloned();
+                let evicted = match memo {
+                    Some(_) => memos.touch(slot),
+                    None => vec![],
+                };
+                (memo, evicted)
+            }
+        };
+        event!(self, Event::ReadMemo, memo);
+        self.log_evictions(evicted);
+        memo
+    }
+
+    /// Calls `f` with the memo for `slot`, if there is one. Unlike `read_memo` this doesn't count as a use of
+    /// the memo, or log anything.
+    fn with_memo<T>(&self, slot: &Slot, f: impl FnOnce(&Memo) -> T) -> Option<T> {
+        match self.provisional_memos.get(slot) {
+            Some(memo) => Some(f(memo)),
+            None => self.shared.memos().storage.get(slot).map(f),
+        }
+    }
+
+    fn log_evictions(&mut self, evicted: Vec<Slot>) {
+        for slot in evicted {
+            event!(self, Event::EvictMemo, slot);
+        }
+    }
+}
+
+/// The view of the database available to query functions.
+///
+/// Query functions can read other queries, but can't set the values of inputs - changing an input part way
+/// through evaluating a query would invalidate the values it has already read.
+pub struct QueryContext<'a> {
+    db: &'a mut Database,
+}
+
+impl QueryContext<'_> {
+    /// Computes or looks up the value for a query, recording it as a dependency of the query being evaluated.
+    ///
+    /// If the query can't be evaluated then the error unwinds through the calling query function to the
+    /// nearest enclosing call to `try_get`, so that it can be handled without aborting the whole process.
+    pub fn get<Q: Query>(&mut self, key: Q::Key) -> Q::Value {
+        match self.try_get::<Q>(key) {
+            Ok(value) => value,
+            Err(error) => self.unwind(error.into()),
+        }
+    }
+
+    /// Computes or looks up the value for a query, recording it as a dependency of the query being evaluated,
+    /// or returns an error if this or any of the queries it reads can't be evaluated.
+    ///
+    /// `DipError::Cancelled` is never returned here: it always unwinds, as with `get`.
+    pub fn try_get<Q: Query>(&mut self, key: Q::Key) -> Result<Q::Value, DipError> {
+        let slot = Slot::new(Q::ID, Key::new(key));
+        self.db.check_query::<Q>(&slot)?;
+
+        match self.db.drive(slot, true) {
+            Ok(stamped) => Ok(stamped.value.unwrap().downcast::<Q::Value>().expect("Value type mismatch")),
+            // A query function which handled this error could return a value computed without the cancelled
+            // read, and we'd memoize it. Instead we unwind, so that no query which was running is memoized.
+            Err(error @ ReadError::Failed(DipError::Cancelled)) => self.unwind(error),
+            Err(ReadError::Failed(error)) => Err(error),
+            // Every active query has been abandoned, including the one running this query function.
+            Err(error) => self.unwind(error),
+        }
+    }
+
+    /// Stashes the reason a read failed and unwinds back to `Database::run_query_function`.
+    fn unwind(&mut self, error: ReadError) -> ! {
+        self.db.unwinding_error = Some(error);
+        panic::resume_unwind(Box::new(QueryFailed))
+    }
+
+    /// Reports an item using the accumulator `A`, tagged with the query being evaluated. The items reported by
+    /// a query and everything it depends on can be collected using `Database::accumulated`.
+    pub fn accumulate<A: Accumulator>(&mut self, item: A::Item) {
+        let item = Value::new(item);
+        event!(self.db, Event::Accumulate, item);
+        let active = self.db.active_queries.last_mut().unwrap();
+        active.accumulated.push((TypeId::of::<A>(), item));
+    }
+}
+
+/// Sets or removes the values of input queries as part of a call to `Database::set_many`.
+///
+/// The methods here behave like the corresponding methods on `Database`, except that only the first write in a
+/// batch moves the database to a new revision, which the rest of the batch shares. As with `Database::set`, an
+/// input whose new value is the same as its old value keeps its old `changed_at` revision.
+pub struct InputBatch<'a> {
+    db: &'a mut Database,
+    /// Whether this batch has started a new revision yet.
+    new_revision: bool,
+}
+
+impl InputBatch<'_> {
+    /// Starts a new revision for this batch, unless it has already done so.
+    ///
+    /// Every revision increase must be accompanied by a write, which updates `Database::last_changed` for low
+    /// durability inputs. Otherwise volatile queries would be rerun at the new revision but memos depending on them
+    /// would still be validated by their durability.
+    fn start_revision(&mut self) {
+        if !self.new_revision {
+            self.db.wait_for_snapshots();
+            self.db.revision += 1;
+            self.new_revision = true;
+        }
+    }
+
+    /// Sets the user-provided value for an input query, with `Durability::Low`.
+    ///
+    /// Panics if `Q` is not an input query.
+    pub fn set<Q: Query>(&mut self, key: Q::Key, value: Q::Value) {
+        self.set_with_durability::<Q>(key, value, Durability::Low)
+    }
+
+    /// Sets the user-provided value for an input query, with `Durability::Low`, or returns an error if `Q` is
+    /// not an input query.
+    pub fn try_set<Q: Query>(&mut self, key: Q::Key, value: Q::Value) -> Result<(), DipError> {
+        self.try_set_with_durability::<Q>(key, value, Durability::Low)
+    }
+
+    /// Sets the user-provided value for an input query, with the given durability.
+    ///
+    /// Panics if `Q` is not an input query.
+    pub fn set_with_durability<Q: Query>(&mut self, key: Q::Key, value: Q::Value, durability: Durability) {
+        self.try_set_with_durability::<Q>(key, value, durability)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Sets the user-provided value for an input query, with the given durability, or returns an error if `Q`
+    /// is not an input query.
+    pub fn try_set_with_durability<Q: Query>(
+        &mut self,
+        key: Q::Key,
+        value: Q::Value,
+        durability: Durability,
+    ) -> Result<(), DipError> {
+        let slot = Slot::new(Q::ID, Key::new(key));
+        self.db.check_input::<Q>(&slot)?;
+        self.start_revision();
+        self.db.write_input(slot, Value::new(value), durability);
+        Ok(())
+    }
+
+    /// Removes the user-provided value for an input query.
+    ///
+    /// Panics if `Q` is not an input query.
+    pub fn remove<Q: Query>(&mut self, key: Q::Key) {
+        self.try_remove::<Q>(key)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Removes the user-provided value for an input query, or returns an error if `Q` is not an input query.
+    pub fn try_remove<Q: Query>(&mut self, key: Q::Key) -> Result<(), DipError> {
+        let slot = Slot::new(Q::ID, Key::new(key));
+        self.db.check_input::<Q>(&slot)?;
+        // As with `Database::try_remove`, removing a value which was never set doesn't need a new revision.
+        if self.db.shared.memos().storage.contains_key(&slot) {
+            self.start_revision();
+            self.db.remove_input(slot);
+        }
+        Ok(())
+    }
+}
+
+/// A read-only view of a `Database`, pinned to the revision at which it was created. See `Database::snapshot`.
+///
+/// Each snapshot keeps its own stack of active queries, so snapshots on different threads can evaluate queries
+/// independently of each other and of the database. The memos they compute are shared.
+///
+/// Reads fail with `DipError::Cancelled` once an input is waiting to be changed.
+pub struct Snapshot {
+    db: Database,
+}
+
+impl Snapshot {
+    /// Computes or looks up the value for a query. See `Database::get`.
+    ///
+    /// Panics if the query can't be evaluated, including if it has been cancelled. See `try_get` for a
+    /// non-panicking version of this method.
+    pub fn get<Q: Query>(&mut self, key: Q::Key) -> Q::Value {
+        self.db.get::<Q>(key)
+    }
+
+    /// Computes or looks up the value for a query, or returns an error if this or any of the queries it reads
+    /// can't be evaluated. See `Database::try_get`.
+    pub fn try_get<Q: Query>(&mut self, key: Q::Key) -> Result<Q::Value, DipError> {
+        self.db.try_get::<Q>(key)
+    }
+
+    /// Returns the items reported using the accumulator `A` by a query and its dependencies. See
+    /// `Database::accumulated`.
+    pub fn accumulated<Q: Query, A: Accumulator>(&mut self, key: Q::Key) -> Vec<(QueryId, Key, A::Item)> {
+        self.db.accumulated::<Q, A>(key)
+    }
+
+    /// Returns the items reported using the accumulator `A` by a query and its dependencies, or returns an error
+    /// if any of these queries can't be evaluated. See `Database::accumulated`.
+    pub fn try_accumulated<Q: Query, A: Accumulator>(
+        &mut self,
+        key: Q::Key,
+    ) -> Result<Vec<(QueryId, Key, A::Item)>, DipError> {
+        self.db.try_accumulated::<Q, A>(key)
+    }
+
+    /// The database revision at which this snapshot was created.
+    pub fn revision(&self) -> usize {
+        self.db.revision
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        *self.db.shared.snapshots.lock().unwrap() -= 1;
+        self.db.shared.snapshot_dropped.notify_all();
+    }
+}
+
+// `Database`s and `Snapshot`s can be sent to and shared between threads.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Database>();
+    assert_send_sync::<Snapshot>();
+};
+
+/// Chooses which derived memos are discarded by `Database::sweep`.
+///
+/// A memo is discarded if it matches any of the criteria set. With no criteria set nothing is discarded.
+#[derive(Default)]
+pub struct Sweep {
+    unverified_since: Option<usize>,
+    roots: Option<Vec<Slot>>,
+    values_only: bool,
+}
+
+impl Sweep {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Discards memos which have not been verified at or after `revision`. See `Database::revision`.
+    pub fn unverified_since(mut self, revision: usize) -> Self {
+        self.unverified_since = Some(revision);
+        self
+    }
+
+    /// Marks the query `Q` with key `key` as a root. If any roots are given then memos which can't be reached
+    /// from a root via the dependencies of memos are discarded.
+    pub fn root<Q: Query>(mut self, key: Q::Key) -> Self {
+        let slot = Slot::new(Q::ID, Key::new(key));
+        self.roots.get_or_insert_with(Vec::new).push(slot);
+        self
+    }
+
+    /// Keeps the memos matching the other criteria, but discards their values.
+    ///
+    /// Memos without values still record when their value last changed, so queries which depend on them don't
+    /// need to rerun unless their dependencies have actually changed. The value is recomputed if it is read again.
+    pub fn values_only(mut self) -> Self {
+        self.values_only = true;
+        self
     }
 }
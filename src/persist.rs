@@ -0,0 +1,204 @@
+//! The `Persist` trait, used to write the keys and values of queries to the files created by `Database::save`,
+//! along with implementations for some common types.
+//!
+//! The encoding is deliberately simple: integers are little-endian and collections are prefixed by their length.
+
+use crate::Durability;
+use std::convert::TryInto;
+
+/// Types which can be written to and read back from the files created by `Database::save`.
+///
+/// Implement this for the key and value types of any query registered using `DatabaseBuilder::persist`.
+pub trait Persist: Sized {
+    /// Identifies the encoding used by `write`. Increase this whenever the encoding changes, so that
+    /// `Database::load` skips memos saved using the old encoding rather than misreading them.
+    const VERSION: u32 = 0;
+
+    /// Appends an encoding of `self` to `out`.
+    fn write(&self, out: &mut Vec<u8>);
+    /// Reads a value written by `write` from the start of `input`, and advances `input` past it. Returns `None`
+    /// if `input` doesn't start with a valid encoding.
+    fn read(input: &mut &[u8]) -> Option<Self>;
+}
+
+/// Reads a value from the whole of `bytes`, or returns `None` if any bytes are left over.
+pub(crate) fn read_exact<T: Persist>(mut bytes: &[u8]) -> Option<T> {
+    let value = T::read(&mut bytes)?;
+    match bytes.is_empty() {
+        true => Some(value),
+        false => None,
+    }
+}
+
+/// Removes the first `len` bytes from `input` and returns them, or returns `None` if `input` is too short.
+pub(crate) fn take<'a>(input: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if input.len() < len {
+        return None;
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    Some(bytes)
+}
+
+macro_rules! persist_int {
+    ($($t:ty),*) => {$(
+        impl Persist for $t {
+            fn write(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn read(input: &mut &[u8]) -> Option<Self> {
+                let bytes = take(input, std::mem::size_of::<$t>())?;
+                Some(<$t>::from_le_bytes(bytes.try_into().unwrap()))
+            }
+        }
+    )*}
+}
+
+persist_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+// `usize` and `isize` are always written as 64 bits, so that files can be shared between platforms.
+
+impl Persist for usize {
+    fn write(&self, out: &mut Vec<u8>) {
+        (*self as u64).write(out)
+    }
+
+    fn read(input: &mut &[u8]) -> Option<Self> {
+        u64::read(input)?.try_into().ok()
+    }
+}
+
+impl Persist for isize {
+    fn write(&self, out: &mut Vec<u8>) {
+        (*self as i64).write(out)
+    }
+
+    fn read(input: &mut &[u8]) -> Option<Self> {
+        i64::read(input)?.try_into().ok()
+    }
+}
+
+impl Persist for bool {
+    fn write(&self, out: &mut Vec<u8>) {
+        (*self as u8).write(out)
+    }
+
+    fn read(input: &mut &[u8]) -> Option<Self> {
+        match u8::read(input)? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl Persist for char {
+    fn write(&self, out: &mut Vec<u8>) {
+        (*self as u32).write(out)
+    }
+
+    fn read(input: &mut &[u8]) -> Option<Self> {
+        char::from_u32(u32::read(input)?)
+    }
+}
+
+impl Persist for String {
+    fn write(&self, out: &mut Vec<u8>) {
+        self.len().write(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn read(input: &mut &[u8]) -> Option<Self> {
+        let len = usize::read(input)?;
+        String::from_utf8(take(input, len)?.to_vec()).ok()
+    }
+}
+
+impl Persist for () {
+    fn write(&self, _: &mut Vec<u8>) {}
+
+    fn read(_: &mut &[u8]) -> Option<Self> {
+        Some(())
+    }
+}
+
+// The versions of types built from other types change whenever the versions of any of their parts change.
+
+impl<T: Persist> Persist for Option<T> {
+    const VERSION: u32 = T::VERSION;
+
+    fn write(&self, out: &mut Vec<u8>) {
+        self.is_some().write(out);
+        if let Some(value) = self {
+            value.write(out);
+        }
+    }
+
+    fn read(input: &mut &[u8]) -> Option<Self> {
+        match bool::read(input)? {
+            true => Some(Some(T::read(input)?)),
+            false => Some(None),
+        }
+    }
+}
+
+impl<T: Persist> Persist for Vec<T> {
+    const VERSION: u32 = T::VERSION;
+
+    fn write(&self, out: &mut Vec<u8>) {
+        self.len().write(out);
+        for item in self {
+            item.write(out);
+        }
+    }
+
+    fn read(input: &mut &[u8]) -> Option<Self> {
+        let len = usize::read(input)?;
+        // Don't trust `len` enough to preallocate with it - a corrupt file could make it arbitrarily large.
+        let mut items = Vec::new();
+        for _ in 0..len {
+            items.push(T::read(input)?);
+        }
+        Some(items)
+    }
+}
+
+macro_rules! persist_tuple {
+    ($($t:ident),*) => {
+        impl<$($t: Persist),*> Persist for ($($t,)*) {
+            // Versions only increase, so the sum increases whenever any of them do.
+            const VERSION: u32 = 0 $(+ $t::VERSION)*;
+
+            #[allow(non_snake_case)]
+            fn write(&self, out: &mut Vec<u8>) {
+                let ($($t,)*) = self;
+                $($t.write(out);)*
+            }
+
+            fn read(input: &mut &[u8]) -> Option<Self> {
+                Some(($($t::read(input)?,)*))
+            }
+        }
+    }
+}
+
+persist_tuple!(A);
+persist_tuple!(A, B);
+persist_tuple!(A, B, C);
+persist_tuple!(A, B, C, D);
+
+impl Persist for Durability {
+    fn write(&self, out: &mut Vec<u8>) {
+        (self.index() as u8).write(out)
+    }
+
+    fn read(input: &mut &[u8]) -> Option<Self> {
+        match u8::read(input)? {
+            0 => Some(Durability::Low),
+            1 => Some(Durability::Medium),
+            2 => Some(Durability::High),
+            _ => None,
+        }
+    }
+}
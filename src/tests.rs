@@ -0,0 +1,981 @@
+//! Tests for behaviour that's hard to see from the walkthrough example, such as errors and threading.
+
+use crate::*;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier};
+
+struct Input;
+impl Query for Input {
+    type Key = u32;
+    type Value = i64;
+    const ID: QueryId = "Input";
+}
+
+/// The sum of the inputs with keys up to and including the key.
+struct Sum;
+impl Query for Sum {
+    type Key = u32;
+    type Value = i64;
+    const ID: QueryId = "Sum";
+}
+
+fn sum(ctx: &mut QueryContext, key: u32) -> i64 {
+    let input = ctx.get::<Input>(key);
+    match key {
+        0 => input,
+        _ => input + ctx.get::<Sum>(key - 1),
+    }
+}
+
+/// Shares the id of `Sum`, but has a different value type.
+struct SumAsString;
+impl Query for SumAsString {
+    type Key = u32;
+    type Value = String;
+    const ID: QueryId = "Sum";
+}
+
+#[test]
+fn cycle_recovery_value_must_match_query_type() {
+    let result = DatabaseBuilder::new()
+        .add_input::<Input>()
+        .add_derived::<Sum>(sum)
+        .cycle_recovery::<SumAsString>(String::new())
+        .build();
+    assert_eq!(result.err(), Some(RegistrationError::TypeMismatch("Sum")));
+}
+
+/// A volatile query, which counts how many times it has been run.
+struct Clock;
+impl Query for Clock {
+    type Key = ();
+    type Value = usize;
+    const ID: QueryId = "Clock";
+}
+
+struct ReadsClock;
+impl Query for ReadsClock {
+    type Key = ();
+    type Value = usize;
+    const ID: QueryId = "ReadsClock";
+}
+
+fn clock_database() -> Database {
+    let ticks = Arc::new(AtomicUsize::new(0));
+    DatabaseBuilder::new()
+        .add_input::<Input>()
+        .add_derived::<Clock>(move |_, ()| ticks.fetch_add(1, Ordering::SeqCst))
+        .volatile::<Clock>()
+        .add_derived::<ReadsClock>(|ctx, ()| ctx.get::<Clock>(()))
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn batches_which_write_nothing_do_not_create_revisions() {
+    let mut db = clock_database();
+    assert_eq!(db.get::<ReadsClock>(()), 0);
+    let revision = db.revision();
+
+    db.set_many(|_| {});
+    db.set_many(|batch| {
+        assert!(batch.try_set::<Clock>((), 0).is_err());
+        batch.remove::<Input>(0);
+    });
+    assert_eq!(db.revision(), revision);
+    assert_eq!(db.get::<ReadsClock>(()), db.get::<Clock>(()));
+
+    db.set_many(|batch| {
+        batch.set::<Input>(0, 1);
+        batch.set::<Input>(1, 2);
+    });
+    assert_eq!(db.revision(), revision + 1);
+    assert_eq!(db.get::<ReadsClock>(()), 1);
+    assert_eq!(db.get::<Clock>(()), 1);
+}
+
+fn sum_database() -> Database {
+    DatabaseBuilder::new()
+        .add_input::<Input>()
+        .add_derived::<Sum>(sum)
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn long_chains_of_queries_do_not_overflow_the_stack() {
+    let mut db = sum_database();
+    db.set_many(|batch| {
+        for key in 0..20_000 {
+            batch.set::<Input>(key, 1);
+        }
+    });
+    assert_eq!(db.get::<Sum>(19_999), 20_000);
+
+    db.set::<Input>(0, 2);
+    assert_eq!(db.get::<Sum>(19_999), 20_001);
+}
+
+#[test]
+fn errors_in_long_chains_report_the_whole_path() {
+    let len = MAX_NESTED_QUERY_FUNCTIONS as u32 * 3;
+    let mut db = sum_database();
+    db.set_many(|batch| {
+        for key in 1..len {
+            batch.set::<Input>(key, 1);
+        }
+    });
+    let path = match db.try_get::<Sum>(len - 1) {
+        Err(DipError::InputNotSet { id: "Input", path, .. }) => path,
+        other => panic!("unexpected result {:?}", other),
+    };
+    let expected: Vec<_> = (0..len)
+        .rev()
+        .map(|key| ("Sum", Key::new(key)))
+        .collect();
+    assert_eq!(path, expected);
+}
+
+/// Each key reads the next, and the last reads the first.
+struct Ring;
+impl Query for Ring {
+    type Key = u32;
+    type Value = ();
+    const ID: QueryId = "Ring";
+}
+
+#[test]
+fn long_cycles_are_detected() {
+    let len = MAX_NESTED_QUERY_FUNCTIONS as u32 * 3;
+    let mut db = DatabaseBuilder::new()
+        .add_derived::<Ring>(move |ctx, key| ctx.get::<Ring>((key + 1) % len))
+        .build()
+        .unwrap();
+    let cycle = match db.try_get::<Ring>(0) {
+        Err(DipError::Cycle { cycle }) => cycle,
+        other => panic!("unexpected result {:?}", other),
+    };
+    assert_eq!(cycle.len(), len as usize);
+    assert!(cycle.iter().all(|(id, _)| *id == "Ring"));
+}
+
+/// Counts how many times it has been run.
+struct Counted;
+impl Query for Counted {
+    type Key = ();
+    type Value = i64;
+    const ID: QueryId = "Counted";
+}
+
+#[test]
+fn memos_are_shared_between_snapshots_and_the_database() {
+    let runs = Arc::new(AtomicUsize::new(0));
+    let counter = runs.clone();
+    let mut db = DatabaseBuilder::new()
+        .add_input::<Input>()
+        .add_derived::<Counted>(move |ctx, ()| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            ctx.get::<Input>(0) * 2
+        })
+        .build()
+        .unwrap();
+    db.set::<Input>(0, 21);
+
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let mut snapshot = db.snapshot();
+            std::thread::spawn(move || snapshot.get::<Counted>(()))
+        })
+        .collect();
+    for thread in threads {
+        assert_eq!(thread.join().unwrap(), 42);
+    }
+    // Whichever thread claims `Counted` first runs it. The others either wait for it to finish, or find its
+    // memo before or after trying to claim it.
+    assert_eq!(db.get::<Counted>(()), 42);
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+    db.set::<Input>(0, 1);
+    let mut snapshot = db.snapshot();
+    assert_eq!(std::thread::spawn(move || snapshot.get::<Counted>(())).join().unwrap(), 2);
+    assert_eq!(db.get::<Counted>(()), 2);
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+}
+
+struct OtherInput;
+impl Query for OtherInput {
+    type Key = ();
+    type Value = i64;
+    const ID: QueryId = "OtherInput";
+}
+
+/// Reads `Input(1)` and falls back to a default if that read fails.
+struct WithFallback;
+impl Query for WithFallback {
+    type Key = ();
+    type Value = i64;
+    const ID: QueryId = "WithFallback";
+}
+
+#[test]
+fn cancelled_queries_are_not_memoized() {
+    // The first run waits part way through for a write to cancel it.
+    let started = Arc::new(Barrier::new(2));
+    let cancelled = Arc::new(Barrier::new(2));
+    let first_run = Arc::new(AtomicBool::new(true));
+    let (started_in_query, cancelled_in_query) = (started.clone(), cancelled.clone());
+    let mut db = DatabaseBuilder::new()
+        .add_input::<Input>()
+        .add_input::<OtherInput>()
+        .add_derived::<WithFallback>(move |ctx, ()| {
+            let input = ctx.get::<Input>(0);
+            if first_run.swap(false, Ordering::SeqCst) {
+                started_in_query.wait();
+                cancelled_in_query.wait();
+            }
+            input + ctx.try_get::<Input>(1).unwrap_or(-1000)
+        })
+        .build()
+        .unwrap();
+    db.set::<Input>(0, 1);
+    db.set::<Input>(1, 2);
+
+    let mut snapshot = db.snapshot();
+    let reader = std::thread::spawn(move || snapshot.try_get::<WithFallback>(()));
+    started.wait();
+    let shared = db.shared.clone();
+    let canceller = std::thread::spawn(move || {
+        while !shared.cancelled.load(Ordering::SeqCst) {
+            std::thread::yield_now();
+        }
+        cancelled.wait();
+    });
+    db.set::<OtherInput>((), 0);
+
+    assert_eq!(reader.join().unwrap(), Err(DipError::Cancelled));
+    canceller.join().unwrap();
+    assert_eq!(db.get::<WithFallback>(()), 3);
+}
+
+/// Reads the query with the opposite key.
+struct Flip;
+impl Query for Flip {
+    type Key = bool;
+    type Value = ();
+    const ID: QueryId = "Flip";
+}
+
+#[test]
+fn cycles_across_threads_are_detected() {
+    // Each key waits the first time it runs, so that both threads have started before either reads the other key.
+    let barrier = Arc::new(Barrier::new(2));
+    let waited = Arc::new([AtomicBool::new(false), AtomicBool::new(false)]);
+    let db = DatabaseBuilder::new()
+        .add_derived::<Flip>(move |ctx, key| {
+            if !waited[key as usize].swap(true, Ordering::SeqCst) {
+                barrier.wait();
+            }
+            ctx.get::<Flip>(!key)
+        })
+        .build()
+        .unwrap();
+
+    let threads: Vec<_> = [false, true]
+        .iter()
+        .map(|&key| {
+            let mut snapshot = db.snapshot();
+            std::thread::spawn(move || snapshot.try_get::<Flip>(key))
+        })
+        .collect();
+    for thread in threads {
+        let cycle = match thread.join().unwrap() {
+            Err(DipError::Cycle { cycle }) => cycle,
+            other => panic!("unexpected result {:?}", other),
+        };
+        assert_eq!(cycle.len(), 2);
+        assert!(cycle.contains(&("Flip", Key::new(false))));
+        assert!(cycle.contains(&("Flip", Key::new(true))));
+    }
+}
+
+/// Twice the input with the same key, counting how many times it has been run.
+struct Double;
+impl Query for Double {
+    type Key = u32;
+    type Value = i64;
+    const ID: QueryId = "Double";
+}
+
+/// The sum of `Double` for every key up to but excluding the key.
+struct SumOfDoubles;
+impl Query for SumOfDoubles {
+    type Key = u32;
+    type Value = i64;
+    const ID: QueryId = "SumOfDoubles";
+}
+
+/// Makes parallel validation use up to `workers` threads, however many cores this machine has.
+fn use_validation_workers(db: &mut Database, workers: usize) {
+    Arc::get_mut(&mut db.shared).unwrap().validation_workers = workers;
+}
+
+#[test]
+fn parallel_validation_agrees_with_sequential_validation() {
+    // Counts the runs of `Double` on threads other than this one.
+    let worker_runs = Arc::new(AtomicUsize::new(0));
+    let counter = worker_runs.clone();
+    let test_thread = std::thread::current().id();
+    let mut db = DatabaseBuilder::new()
+        .add_input::<Input>()
+        .add_derived::<Double>(move |ctx, key| {
+            if std::thread::current().id() != test_thread {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+            ctx.get::<Input>(key) * 2
+        })
+        .add_derived::<SumOfDoubles>(|ctx, len| (0..len).map(|key| ctx.get::<Double>(key)).sum())
+        .parallel_validation::<SumOfDoubles>()
+        .build()
+        .unwrap();
+    use_validation_workers(&mut db, 4);
+    db.set_many(|batch| {
+        for key in 0..100 {
+            batch.set::<Input>(key, 1);
+        }
+    });
+    assert_eq!(db.get::<SumOfDoubles>(100), 200);
+
+    // Nothing `SumOfDoubles` reads has changed, so every worker reads all of its block.
+    db.set::<Input>(100, 1);
+    assert_eq!(db.get::<SumOfDoubles>(100), 200);
+    assert_eq!(worker_runs.load(Ordering::SeqCst), 0);
+
+    // The worker which reads the changed dependency reruns it.
+    for &key in [0, 50, 99].iter() {
+        db.set::<Input>(key, 2);
+        assert_eq!(db.get::<SumOfDoubles>(100), 202);
+        db.set::<Input>(key, 1);
+        assert_eq!(db.get::<SumOfDoubles>(100), 200);
+    }
+    assert_eq!(worker_runs.load(Ordering::SeqCst), 6);
+}
+
+struct Len;
+impl Query for Len {
+    type Key = ();
+    type Value = u32;
+    const ID: QueryId = "Len";
+}
+
+/// The value of `Len`, read through a derived query so that checking it doesn't find that it has changed
+/// without starting validation workers.
+struct ItemCount;
+impl Query for ItemCount {
+    type Key = ();
+    type Value = u32;
+    const ID: QueryId = "ItemCount";
+}
+
+/// Panics unless its key is less than `Len`.
+struct Item;
+impl Query for Item {
+    type Key = u32;
+    type Value = u32;
+    const ID: QueryId = "Item";
+}
+
+/// The sum of `Item` for every key less than `ItemCount`.
+struct SumOfItems;
+impl Query for SumOfItems {
+    type Key = ();
+    type Value = u32;
+    const ID: QueryId = "SumOfItems";
+}
+
+#[test]
+fn parallel_validation_ignores_panics_after_a_changed_dependency() {
+    // Once the length has shrunk, `ItemCount` waits until another worker has read an item past the end, so that
+    // the read isn't cancelled first.
+    let out_of_bounds = Arc::new(AtomicBool::new(false));
+    let out_of_bounds_read = out_of_bounds.clone();
+    let mut db = DatabaseBuilder::new()
+        .add_input::<Len>()
+        .add_derived::<Item>(move |ctx, key| {
+            if key >= ctx.get::<Len>(()) {
+                out_of_bounds.store(true, Ordering::SeqCst);
+                panic!("Item({}) is out of bounds", key);
+            }
+            key
+        })
+        .add_derived::<ItemCount>(move |ctx, ()| {
+            let len = ctx.get::<Len>(());
+            while len < 100 && !out_of_bounds_read.load(Ordering::SeqCst) {
+                std::thread::yield_now();
+            }
+            len
+        })
+        .add_derived::<SumOfItems>(|ctx, ()| (0..ctx.get::<ItemCount>(())).map(|key| ctx.get::<Item>(key)).sum())
+        .parallel_validation::<SumOfItems>()
+        .build()
+        .unwrap();
+    use_validation_workers(&mut db, 4);
+    db.set::<Len>((), 100);
+    assert_eq!(db.get::<SumOfItems>(()), 4950);
+
+    // The rerun doesn't read the items which panicked.
+    db.set::<Len>((), 10);
+    assert_eq!(db.get::<SumOfItems>(()), 45);
+    assert_eq!(db.get::<SumOfItems>(()), 45);
+}
+
+/// A path in the temporary directory which is unique to this process and test.
+fn save_path(test: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("dip-{}-{}", std::process::id(), test))
+}
+
+/// Registers `Input`, `SumOfDoubles`, and `Double`, which counts its runs using `runs`.
+fn doubles_builder(runs: Arc<AtomicUsize>) -> DatabaseBuilder {
+    DatabaseBuilder::new()
+        .add_input::<Input>()
+        .add_derived::<Double>(move |ctx, key| {
+            runs.fetch_add(1, Ordering::SeqCst);
+            ctx.get::<Input>(key) * 2
+        })
+        .add_derived::<SumOfDoubles>(|ctx, len| (0..len).map(|key| ctx.get::<Double>(key)).sum())
+}
+
+fn persisted_database(runs: Arc<AtomicUsize>) -> Database {
+    doubles_builder(runs)
+        .persist::<Input>()
+        .persist::<Double>()
+        .persist::<SumOfDoubles>()
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn loaded_memos_are_reused() {
+    let path = save_path("loaded_memos_are_reused");
+    let mut db = persisted_database(Arc::new(AtomicUsize::new(0)));
+    db.set_many(|batch| {
+        for key in 0..3 {
+            batch.set::<Input>(key, key as i64);
+        }
+    });
+    assert_eq!(db.get::<SumOfDoubles>(3), 6);
+    db.save(&path).unwrap();
+
+    let runs = Arc::new(AtomicUsize::new(0));
+    let mut loaded = persisted_database(runs.clone());
+    loaded.load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(loaded.revision() > db.revision());
+    assert_eq!(loaded.get::<SumOfDoubles>(3), 6);
+    assert_eq!(runs.load(Ordering::SeqCst), 0);
+
+    loaded.set::<Input>(1, 10);
+    assert_eq!(loaded.get::<SumOfDoubles>(3), 24);
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+}
+
+/// A key whose encoding has a byte after the end of the value that `read` reads.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Padded(u32);
+impl Persist for Padded {
+    fn write(&self, out: &mut Vec<u8>) {
+        self.0.write(out);
+        out.push(0);
+    }
+
+    fn read(input: &mut &[u8]) -> Option<Self> {
+        Some(Padded(u32::read(input)?))
+    }
+}
+
+struct PaddedInput;
+impl Query for PaddedInput {
+    type Key = Padded;
+    type Value = i64;
+    const ID: QueryId = "PaddedInput";
+}
+
+#[test]
+fn loading_rejects_keys_with_trailing_bytes() {
+    let path = save_path("loading_rejects_keys_with_trailing_bytes");
+    let database = || {
+        DatabaseBuilder::new()
+            .add_input::<PaddedInput>()
+            .persist::<PaddedInput>()
+            .build()
+            .unwrap()
+    };
+    let mut db = database();
+    db.set::<PaddedInput>(Padded(1), 1);
+    db.save(&path).unwrap();
+
+    let result = database().load(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert!(matches!(result, Err(LoadError::Malformed)));
+}
+
+/// Reads `CycleB`, and is cycle-tolerant with an initial value of 0.
+struct CycleA;
+impl Query for CycleA {
+    type Key = ();
+    type Value = i64;
+    const ID: QueryId = "CycleA";
+}
+
+/// Reads `CycleA`, and then `Input(0)` unless `CycleA` is 0.
+struct CycleB;
+impl Query for CycleB {
+    type Key = ();
+    type Value = i64;
+    const ID: QueryId = "CycleB";
+}
+
+fn cycle_database() -> Database {
+    DatabaseBuilder::new()
+        .add_input::<Input>()
+        .add_derived::<CycleA>(|ctx, ()| ctx.get::<CycleB>(()))
+        .cycle_recovery::<CycleA>(0)
+        .add_derived::<CycleB>(|ctx, ()| match ctx.get::<CycleA>(()) {
+            0 => 7,
+            _ => ctx.get::<Input>(0),
+        })
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn cycles_through_cycle_tolerant_queries_reach_a_fixpoint() {
+    let mut db = cycle_database();
+    db.set::<Input>(0, 7);
+    assert_eq!(db.get::<CycleA>(()), 7);
+    assert_eq!(db.get::<CycleB>(()), 7);
+
+    db.set::<Input>(0, 9);
+    assert_eq!(db.get::<CycleA>(()), 9);
+    assert_eq!(db.get::<CycleB>(()), 9);
+
+    let mut fresh = cycle_database();
+    fresh.set::<Input>(0, 9);
+    assert_eq!(fresh.get::<CycleA>(()), 9);
+}
+
+/// Counts upwards each time it's re-entered, so never reaches a fixpoint.
+struct Counter;
+impl Query for Counter {
+    type Key = ();
+    type Value = usize;
+    const ID: QueryId = "Counter";
+}
+
+#[test]
+fn cycles_which_do_not_converge_are_reported() {
+    let mut db = DatabaseBuilder::new()
+        .add_derived::<Counter>(|ctx, ()| ctx.get::<Counter>(()) + 1)
+        .cycle_recovery::<Counter>(0)
+        .build()
+        .unwrap();
+    assert!(matches!(
+        db.try_get::<Counter>(()),
+        Err(DipError::FixpointDidNotConverge { id: "Counter", .. })
+    ));
+}
+
+/// The revision at which the memo for `Q` with key `key` was last verified, if it has a memo.
+fn verified_at<Q: Query>(db: &Database, key: Q::Key) -> Option<usize> {
+    db.with_memo(&Slot::new(Q::ID, Key::new(key)), |memo| memo.verified_at)
+}
+
+#[test]
+fn memos_of_durable_inputs_are_valid_without_checking_dependencies() {
+    let mut db = sum_database();
+    db.set_with_durability::<Input>(0, 1, Durability::High);
+    db.set_with_durability::<Input>(1, 2, Durability::Medium);
+    db.set::<Input>(2, 3);
+    assert_eq!(db.get::<Sum>(1), 3);
+
+    // `Sum(1)` only reads inputs with at least medium durability, so changing a low durability input doesn't
+    // require reading its dependencies.
+    db.set::<Input>(2, 4);
+    assert_eq!(db.get::<Sum>(1), 3);
+    assert_eq!(verified_at::<Sum>(&db, 1), Some(db.revision()));
+    assert!(verified_at::<Sum>(&db, 0) < Some(db.revision()));
+
+    // Changing a medium durability input means checking the dependencies of `Sum(1)` again, but `Sum(0)` is
+    // still valid without checking its own.
+    db.set_with_durability::<Input>(1, 5, Durability::Medium);
+    assert_eq!(db.get::<Sum>(1), 6);
+    assert_eq!(verified_at::<Sum>(&db, 0), Some(db.revision()));
+    assert!(verified_at::<Input>(&db, 0) < Some(db.revision()));
+}
+
+/// A volatile query whose value is always the same.
+struct Constant;
+impl Query for Constant {
+    type Key = ();
+    type Value = i64;
+    const ID: QueryId = "Constant";
+}
+
+#[test]
+fn volatile_queries_are_rerun_at_each_revision() {
+    let mut db = clock_database();
+    assert_eq!(db.get::<ReadsClock>(()), 0);
+    assert_eq!(db.get::<Clock>(()), 0);
+
+    db.set::<Input>(0, 1);
+    assert_eq!(db.get::<ReadsClock>(()), 1);
+    assert_eq!(db.get::<Clock>(()), 1);
+
+    // Rerunning a volatile query which returns the same value doesn't rerun the queries which read it.
+    let runs = Arc::new(AtomicUsize::new(0));
+    let counter = runs.clone();
+    let mut db = DatabaseBuilder::new()
+        .add_input::<Input>()
+        .add_derived::<Constant>(|_, ()| 1)
+        .volatile::<Constant>()
+        .add_derived::<Counted>(move |ctx, ()| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            ctx.get::<Constant>(())
+        })
+        .build()
+        .unwrap();
+    assert_eq!(db.get::<Counted>(()), 1);
+    db.set::<Input>(0, 1);
+    assert_eq!(db.get::<Counted>(()), 1);
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn removing_an_input_invalidates_the_queries_which_read_it() {
+    let mut db = sum_database();
+    db.set::<Input>(0, 1);
+    db.set::<Input>(1, 2);
+    assert_eq!(db.get::<Sum>(1), 3);
+
+    db.remove::<Input>(0);
+    let expected_path = vec![("Sum", Key::new(1u32)), ("Sum", Key::new(0u32))];
+    assert_eq!(
+        db.try_get::<Sum>(1),
+        Err(DipError::InputNotSet {
+            id: "Input",
+            key: Key::new(0u32),
+            path: expected_path,
+        })
+    );
+
+    db.set::<Input>(0, 5);
+    assert_eq!(db.get::<Sum>(1), 7);
+}
+
+#[test]
+fn least_recently_used_memos_are_evicted() {
+    let result = DatabaseBuilder::new()
+        .add_derived::<Counted>(|_, ()| 0)
+        .lru_capacity::<Counted>(0)
+        .build();
+    assert_eq!(result.err(), Some(RegistrationError::ZeroLruCapacity("Counted")));
+
+    let runs = Arc::new(AtomicUsize::new(0));
+    let counter = runs.clone();
+    let mut db = DatabaseBuilder::new()
+        .add_input::<Input>()
+        .add_derived::<Double>(move |ctx, key| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            ctx.get::<Input>(key) * 2
+        })
+        .lru_capacity::<Double>(2)
+        .build()
+        .unwrap();
+    for key in 0..3 {
+        db.set::<Input>(key, key as i64);
+    }
+    assert_eq!(db.get::<Double>(0), 0);
+    assert_eq!(db.get::<Double>(1), 2);
+    assert_eq!(db.get::<Double>(0), 0);
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+    // `Double(1)` is the least recently used, so storing `Double(2)` evicts it.
+    assert_eq!(db.get::<Double>(2), 4);
+    assert_eq!(verified_at::<Double>(&db, 1), None);
+    assert_eq!(db.get::<Double>(0), 0);
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+    assert_eq!(db.get::<Double>(1), 2);
+    assert_eq!(runs.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn sweeping_discards_memos() {
+    let runs = Arc::new(AtomicUsize::new(0));
+    let mut db = doubles_builder(runs.clone()).build().unwrap();
+    for key in 0..5 {
+        db.set::<Input>(key, key as i64);
+    }
+    assert_eq!(db.get::<SumOfDoubles>(3), 6);
+    assert_eq!(db.get::<SumOfDoubles>(1), 0);
+    assert_eq!(db.get::<Double>(4), 8);
+
+    db.set::<Input>(4, 5);
+    let revision = db.revision();
+    assert_eq!(db.get::<SumOfDoubles>(3), 6);
+    db.sweep(Sweep::new().unverified_since(revision));
+    assert_eq!(verified_at::<Double>(&db, 4), None);
+    assert_eq!(verified_at::<SumOfDoubles>(&db, 1), None);
+    assert_eq!(verified_at::<Double>(&db, 0), Some(revision));
+
+    db.sweep(Sweep::new().root::<Double>(0));
+    assert_eq!(verified_at::<SumOfDoubles>(&db, 3), None);
+    assert_eq!(verified_at::<Double>(&db, 1), None);
+    assert_eq!(verified_at::<Double>(&db, 0), Some(revision));
+    assert_eq!(verified_at::<Input>(&db, 1), Some(revision));
+
+    // Memos whose values are discarded can still be validated, and their values are recomputed when read.
+    db.sweep(Sweep::new().unverified_since(revision + 1).values_only());
+    assert_eq!(verified_at::<Double>(&db, 0), Some(revision));
+    let runs_before = runs.load(Ordering::SeqCst);
+    assert_eq!(db.get::<Double>(0), 0);
+    assert_eq!(runs.load(Ordering::SeqCst), runs_before + 1);
+}
+
+#[test]
+fn dependencies_only_memos_are_validated_without_their_values() {
+    let runs = Arc::new(AtomicUsize::new(0));
+    let mut db = doubles_builder(runs.clone())
+        .dependencies_only::<Double>()
+        .build()
+        .unwrap();
+    for key in 0..5 {
+        db.set::<Input>(key, key as i64);
+    }
+    assert_eq!(db.get::<SumOfDoubles>(3), 6);
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+    let value = db.with_memo(&Slot::new("Double", Key::new(0u32)), |memo| memo.value.clone());
+    assert_eq!(value, Some(None));
+
+    db.set::<Input>(4, 5);
+    assert_eq!(db.get::<SumOfDoubles>(3), 6);
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+
+    assert_eq!(db.get::<Double>(0), 0);
+    assert_eq!(runs.load(Ordering::SeqCst), 4);
+
+    db.set::<Input>(1, 5);
+    assert_eq!(db.get::<SumOfDoubles>(3), 14);
+}
+
+#[test]
+fn transparent_queries_pass_their_dependencies_to_the_queries_reading_them() {
+    let runs = Arc::new(AtomicUsize::new(0));
+    let mut db = doubles_builder(runs.clone())
+        .transparent::<Double>()
+        .build()
+        .unwrap();
+    for key in 0..3 {
+        db.set::<Input>(key, key as i64);
+    }
+    assert_eq!(db.get::<SumOfDoubles>(2), 2);
+    assert_eq!(verified_at::<Double>(&db, 0), None);
+    let dependencies = db.with_memo(&Slot::new("SumOfDoubles", Key::new(2u32)), |memo| {
+        memo.dependencies.clone()
+    });
+    let expected = vec![Slot::new("Input", Key::new(0u32)), Slot::new("Input", Key::new(1u32))];
+    assert_eq!(dependencies, Some(expected));
+
+    // Transparent queries are rerun every time they're read, but not when validating the queries reading them.
+    assert_eq!(db.get::<Double>(1), 2);
+    assert_eq!(db.get::<Double>(1), 2);
+    assert_eq!(runs.load(Ordering::SeqCst), 4);
+    db.set::<Input>(2, 5);
+    assert_eq!(db.get::<SumOfDoubles>(2), 2);
+    assert_eq!(runs.load(Ordering::SeqCst), 4);
+    db.set::<Input>(1, 5);
+    assert_eq!(db.get::<SumOfDoubles>(2), 10);
+    assert_eq!(runs.load(Ordering::SeqCst), 6);
+}
+
+#[test]
+fn dependents_are_tracked_as_memos_change() {
+    let mut db = sum_database();
+    for key in 0..3 {
+        db.set::<Input>(key, 1);
+    }
+    assert_eq!(db.get::<Sum>(2), 3);
+    let sorted = |mut dependents: Vec<(QueryId, Key)>| {
+        dependents.sort_by_key(|(id, key)| (*id, *key.downcast_ref::<u32>().unwrap()));
+        dependents
+    };
+    assert_eq!(db.dependents_of::<Input>(0), vec![("Sum", Key::new(0u32))]);
+    assert_eq!(db.dependents_of::<Sum>(2), vec![]);
+    assert_eq!(
+        sorted(db.transitive_dependents_of::<Input>(1)),
+        vec![("Sum", Key::new(1u32)), ("Sum", Key::new(2u32))]
+    );
+
+    // Discarding a memo removes it from the dependents of what it read.
+    db.sweep(Sweep::new().root::<Sum>(1));
+    assert_eq!(db.dependents_of::<Sum>(1), vec![]);
+    assert_eq!(
+        sorted(db.transitive_dependents_of::<Input>(0)),
+        vec![("Sum", Key::new(0u32)), ("Sum", Key::new(1u32))]
+    );
+}
+
+#[test]
+fn subscribers_are_notified_when_values_change() {
+    let runs = Arc::new(AtomicUsize::new(0));
+    let mut db = doubles_builder(runs).transparent::<Double>().build().unwrap();
+    for key in 0..3 {
+        db.set::<Input>(key, key as i64);
+    }
+    let seen = Arc::new(std::sync::Mutex::new(vec![]));
+    let sink = seen.clone();
+    let id = db.subscribe::<SumOfDoubles>(2, move |value| sink.lock().unwrap().push(*value));
+    assert_eq!(
+        db.try_subscribe::<Double>(0, |_| {}),
+        Err(DipError::Transparent {
+            id: "Double",
+            key: Key::new(0u32),
+        })
+    );
+
+    // Only changes to the value are reported, and not changes to inputs which leave the value the same.
+    db.flush();
+    db.set::<Input>(2, 5);
+    db.flush();
+    db.set::<Input>(1, 4);
+    db.flush();
+    db.set::<Input>(0, 1);
+    db.set::<Input>(1, 3);
+    db.flush();
+    assert_eq!(*seen.lock().unwrap(), vec![8]);
+
+    db.unsubscribe(id);
+    db.set::<Input>(0, 2);
+    db.flush();
+    assert_eq!(*seen.lock().unwrap(), vec![8]);
+}
+
+/// A transparent version of `Sum`.
+struct TransparentSum;
+impl Query for TransparentSum {
+    type Key = u32;
+    type Value = i64;
+    const ID: QueryId = "TransparentSum";
+}
+
+#[test]
+fn long_chains_of_transparent_queries_do_not_overflow_the_stack() {
+    let mut db = DatabaseBuilder::new()
+        .add_input::<Input>()
+        .add_derived::<TransparentSum>(|ctx, key| {
+            let input = ctx.get::<Input>(key);
+            match key {
+                0 => input,
+                _ => input + ctx.get::<TransparentSum>(key - 1),
+            }
+        })
+        .transparent::<TransparentSum>()
+        .add_derived::<Sum>(|ctx, key| ctx.get::<TransparentSum>(key))
+        .build()
+        .unwrap();
+    db.set_many(|batch| {
+        for key in 0..5000 {
+            batch.set::<Input>(key, 1);
+        }
+    });
+    assert_eq!(db.get::<Sum>(4999), 5000);
+
+    db.set::<Input>(0, 2);
+    assert_eq!(db.get::<Sum>(4999), 5001);
+}
+
+struct Warnings;
+impl Accumulator for Warnings {
+    type Item = String;
+}
+
+/// The input with the same key, warning if it's negative.
+struct Validated;
+impl Query for Validated {
+    type Key = u32;
+    type Value = i64;
+    const ID: QueryId = "Validated";
+}
+
+/// A transparent wrapper around `Validated`, which reports each key it checks.
+struct Checked;
+impl Query for Checked {
+    type Key = u32;
+    type Value = i64;
+    const ID: QueryId = "Checked";
+}
+
+#[test]
+fn accumulated_items_are_collected_from_dependencies() {
+    let mut db = DatabaseBuilder::new()
+        .add_input::<Input>()
+        .add_derived::<Validated>(|ctx, key| {
+            let value = ctx.get::<Input>(key);
+            if value < 0 {
+                ctx.accumulate::<Warnings>(format!("{} is negative", key));
+            }
+            value
+        })
+        .add_derived::<Checked>(|ctx, key| {
+            ctx.accumulate::<Warnings>(format!("checked {}", key));
+            ctx.get::<Validated>(key)
+        })
+        .transparent::<Checked>()
+        .add_derived::<Sum>(|ctx, len| (0..len).map(|key| ctx.get::<Checked>(key)).sum())
+        .build()
+        .unwrap();
+    db.set::<Input>(0, -1);
+    db.set::<Input>(1, 2);
+
+    let warning = |id, key: u32, warning: &str| (id, Key::new(key), warning.to_string());
+    assert_eq!(
+        db.accumulated::<Sum, Warnings>(2),
+        vec![
+            warning("Sum", 2, "checked 0"),
+            warning("Sum", 2, "checked 1"),
+            warning("Validated", 0, "0 is negative"),
+        ]
+    );
+
+    db.set::<Input>(0, 1);
+    assert_eq!(
+        db.accumulated::<Sum, Warnings>(2),
+        vec![warning("Sum", 2, "checked 0"), warning("Sum", 2, "checked 1")]
+    );
+    assert_eq!(
+        db.try_accumulated::<Checked, Warnings>(0),
+        Err(DipError::Transparent {
+            id: "Checked",
+            key: Key::new(0u32),
+        })
+    );
+}
+
+#[test]
+fn queries_read_on_several_threads_are_only_run_once() {
+    let runs = Arc::new(AtomicUsize::new(0));
+    let mut db = doubles_builder(runs.clone()).build().unwrap();
+    db.set_many(|batch| {
+        for key in 0..500 {
+            batch.set::<Input>(key, 1);
+        }
+    });
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let mut snapshot = db.snapshot();
+            std::thread::spawn(move || (0..500).map(|key| snapshot.get::<Double>(key)).sum::<i64>())
+        })
+        .collect();
+    for thread in threads {
+        assert_eq!(thread.join().unwrap(), 1000);
+    }
+    assert_eq!(runs.load(Ordering::SeqCst), 500);
+}